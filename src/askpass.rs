@@ -0,0 +1,125 @@
+//! Askpass helper answering prompts of git and ssh with credentials from config.
+//! Git and ssh run askpass program with the prompt as the only argument, so
+//! freshgit is passed as askpass program itself and credentials are passed to it
+//! through environment of git process, never through arguments.
+use std::env;
+use std::ffi::OsString;
+
+/// Name of hidden askpass subcommand.
+pub const SUBCOMMAND: &str = "askpass";
+/// Set for git processes, tells freshgit it was run by git or ssh as askpass.
+pub const ENV_ASKPASS: &str = "FRESHGIT_ASKPASS";
+// Answers have their own prefix, `FRESHGIT_<FIELD>` variables override config
+pub const ENV_USERNAME: &str = "FRESHGIT_ASKPASS_USERNAME";
+pub const ENV_PASSWORD: &str = "FRESHGIT_ASKPASS_PASSWORD";
+pub const ENV_PASSPHRASE: &str = "FRESHGIT_ASKPASS_PASSPHRASE";
+
+/// Exit code when the prompt was answered.
+const EXIT_ANSWERED: i32 = 0;
+/// Exit code when the prompt is unknown or there is nothing to answer it with,
+/// git and ssh treat it as cancelled prompt.
+const EXIT_NO_ANSWER: i32 = 1;
+
+/// Turns `freshgit <prompt>` run by git or ssh into `freshgit askpass <prompt>`,
+/// other arguments are returned as is.
+pub fn args<I: IntoIterator<Item = OsString>>(args: I) -> Vec<OsString> {
+    with_subcommand(
+        args.into_iter().collect(),
+        env::var_os(ENV_ASKPASS).is_some(),
+    )
+}
+
+fn with_subcommand(mut args: Vec<OsString>, askpass: bool) -> Vec<OsString> {
+    if askpass && args.len() == 2 && args[1] != SUBCOMMAND {
+        args.insert(1, OsString::from(SUBCOMMAND));
+    }
+
+    args
+}
+
+/// Prints answer to the prompt taken from environment and returns exit code of
+/// askpass.
+pub fn run(prompt: &str) -> i32 {
+    match answer(prompt, |var| env::var(var).ok()) {
+        Some(a) => {
+            println!("{}", a);
+            EXIT_ANSWERED
+        }
+        None => EXIT_NO_ANSWER,
+    }
+}
+
+/// Finds answer to the prompt, `var` looks up value of answer variable. Host key
+/// confirmations and other questions are never answered, neither are prompts
+/// with empty answer.
+fn answer<F: Fn(&str) -> Option<String>>(prompt: &str, var: F) -> Option<String> {
+    let p = prompt.trim().to_lowercase();
+    let name = if p.starts_with("username for '") {
+        ENV_USERNAME
+    } else if p.starts_with("password for '") || p.ends_with("'s password:") {
+        ENV_PASSWORD
+    } else if p.starts_with("enter passphrase") {
+        ENV_PASSPHRASE
+    } else {
+        return None;
+    };
+
+    var(name).filter(|a| !a.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answers(var: &str) -> Option<String> {
+        match var {
+            ENV_USERNAME => Some("user".to_string()),
+            ENV_PASSWORD => Some("pass".to_string()),
+            _ => Some(String::new()),
+        }
+    }
+
+    #[test]
+    fn answers_prompts_of_git_and_ssh() {
+        let ans = |p: &str| answer(p, answers);
+        assert_eq!(
+            ans("Username for 'https://host': "),
+            Some("user".to_string())
+        );
+        assert_eq!(
+            ans("Password for 'https://user@host': "),
+            Some("pass".to_string())
+        );
+        assert_eq!(ans("user@host's password: "), Some("pass".to_string()));
+    }
+
+    #[test]
+    fn doesnt_answer_unknown_or_empty() {
+        let ans = |p: &str| answer(p, answers);
+        assert_eq!(ans("Enter passphrase for key '/home/u/.ssh/id': "), None);
+        assert_eq!(
+            ans("Are you sure you want to continue connecting (yes/no)? "),
+            None
+        );
+        assert_eq!(answer("Password for 'https://host': ", |_| None), None);
+    }
+
+    #[test]
+    fn inserts_subcommand_for_askpass() {
+        let args =
+            |a: &[&str], askpass| with_subcommand(a.iter().map(OsString::from).collect(), askpass);
+        assert_eq!(
+            args(&["freshgit", "Password: "], true),
+            vec!["freshgit", SUBCOMMAND, "Password: "]
+        );
+        assert_eq!(args(&["freshgit", "-u"], false), vec!["freshgit", "-u"]);
+        assert_eq!(
+            args(&["freshgit", SUBCOMMAND], true),
+            vec!["freshgit", SUBCOMMAND]
+        );
+        assert_eq!(
+            args(&["freshgit", "-c", "c.json", "-u"], true),
+            vec!["freshgit", "-c", "c.json", "-u"]
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Checks config and repository lists without running git, so problems are found
+//! before any repository is touched. Result is printed to stdout, problems are
+//! counted and the run fails if there is at least one.
+use crate::dl_upd::read_config;
+use crate::error::FreshgitError;
+use crate::lists;
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// Name of the file created to check whether source folder is writable.
+const PROBE_FILE: &str = ".freshgit-check";
+
+/// Entry of a list where remote address was found.
+struct Place {
+    addr: String,
+    file: PathBuf,
+    line: usize,
+}
+
+impl Place {
+    fn at(&self) -> String {
+        format!("{}:{}", self.file.display(), self.line)
+    }
+}
+
+/// Checks config passed with `-c/--config`, returns amount of problems found.
+pub fn check_config(matches: &ArgMatches) -> Result<usize, FreshgitError> {
+    let conf = read_config(matches, matches.value_of("profile"))?;
+    let mut problems = 0;
+    let mut problem = |msg: String| {
+        println!("  problem: {}", msg);
+        problems += 1;
+    };
+
+    let src_folder = conf.src_folder.unwrap_or_default();
+    println!("Source folder: {}", src_folder.display());
+    if !src_folder.is_dir() {
+        problem("folder doesn't exist".to_string());
+    } else if let Err(e) = check_writable(&src_folder) {
+        problem(format!("folder is not writable: {}", e));
+    }
+
+    let csv_column = conf.csv_column.unwrap_or("repository".to_string());
+    let mut places = BTreeMap::<PathBuf, Vec<Place>>::new();
+    for f in conf.files_to_read.unwrap_or_default() {
+        println!("List: {}", f.display());
+        if !f.is_file() {
+            problem("file doesn't exist".to_string());
+            continue;
+        }
+        let entries = match lists::read_list(&f, &csv_column) {
+            Ok(en) => en,
+            Err(e) => {
+                problem(e);
+                continue;
+            }
+        };
+
+        let valid = entries.iter().filter(|en| en.remote.is_ok()).count();
+        println!("  {} valid, {} invalid", valid, entries.len() - valid);
+        for en in entries {
+            match en.remote {
+                Ok(ra) => places
+                    .entry(ra.target_dir(&src_folder))
+                    .or_default()
+                    .push(Place {
+                        addr: ra.to_string(),
+                        file: f.clone(),
+                        line: en.line,
+                    }),
+                Err(e) => problem(format!("line {}: {}", en.line, e)),
+            }
+        }
+    }
+
+    println!(
+        "Repositories: {}",
+        places.values().map(|ps| ps.len()).sum::<usize>()
+    );
+    // Same address is a duplicate, different addresses are cloned to the same folder
+    for (dir, ps) in places.iter().filter(|(_, ps)| ps.len() > 1) {
+        let mut by_addr = BTreeMap::<&str, Vec<&Place>>::new();
+        for p in ps {
+            by_addr.entry(p.addr.as_str()).or_default().push(p);
+        }
+
+        for (addr, ps) in by_addr.iter().filter(|(_, ps)| ps.len() > 1) {
+            let at: Vec<String> = ps.iter().map(|p| p.at()).collect();
+            problem(format!("duplicate {}: {}", addr, at.join(", ")));
+        }
+        if by_addr.len() > 1 {
+            let at: Vec<String> = by_addr
+                .iter()
+                .map(|(addr, ps)| format!("{} ({})", addr, ps[0].at()))
+                .collect();
+            problem(format!(
+                "repositories collide in {}: {}",
+                dir.display(),
+                at.join(", ")
+            ));
+        }
+    }
+
+    match problems {
+        0 => println!("Config is valid"),
+        n => println!("Config has {} problems", n),
+    }
+
+    Ok(problems)
+}
+
+/// Creates and removes a file in the folder, permissions alone don't tell it,
+/// e.g. for read-only file systems.
+pub fn check_writable(folder: &Path) -> std::io::Result<()> {
+    let probe = folder.join(format!("{}-{}", PROBE_FILE, std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)?;
+    fs::remove_file(&probe)
+}
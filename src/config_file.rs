@@ -0,0 +1,658 @@
+//! Config files can be written in json, toml or yaml. Format is picked by the file
+//! extension and every format gives the same `Config`, so comments can be kept in
+//! toml and yaml configs. Files can be converted between formats, comments are
+//! lost by conversion.
+//!
+//! Config is merged from several layers, so system-wide base config can be
+//! overridden per user: `/etc/freshgit/config.*`, `$XDG_CONFIG_HOME/freshgit/config.*`,
+//! `./freshgit.toml`, file of `FRESHGIT_CONFIG` and file of `-c/--config`, later
+//! layers take precedence. Tables are merged key by key, other values replace
+//! values of lower layers.
+//!
+//! Config can define named profiles in `profiles` table, every profile is a config
+//! itself, its values override values set outside of `profiles`.
+use crate::dl_upd::Config;
+use crate::error::FreshgitError;
+use crate::secret;
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Environment variable with path of config.
+pub const ENV_CONFIG: &str = "FRESHGIT_CONFIG";
+/// Config of the current folder.
+pub const LOCAL_CONFIG: &str = "freshgit.toml";
+/// System-wide config folder.
+const SYSTEM_DIR: &str = "/etc/freshgit";
+/// Name of config file in user and system folders, with any supported extension.
+const CONFIG_NAME: &str = "config";
+/// Extensions searched in user and system folders, in this order.
+const EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+/// Table with profiles.
+const PROFILES: &str = "profiles";
+/// Config fields which are never shown.
+const SECRET_KEYS: [&str; 3] = ["git_password", "ssh_askpass", "token"];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// Format of the file by its extension, files with other extensions are read
+    /// as json, as they were before other formats were supported.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+        }
+    }
+
+    /// Parses `content` of the file at `path`, errors keep position of the problem.
+    pub fn parse<T: DeserializeOwned>(
+        self,
+        path: &Path,
+        content: &str,
+    ) -> Result<T, FreshgitError> {
+        match self {
+            Format::Json => serde_json::from_str(content).map_err(|e| {
+                let position = Some((e.line(), e.column()));
+                let syntax = e.classify() != Category::Data;
+                FreshgitError::parse(path, self.name(), e.to_string(), position, syntax)
+            }),
+            // Errors of toml and yaml are not categorized, so content is parsed
+            // again without config fields to tell broken syntax from wrong values
+            Format::Toml => toml::from_str(content).map_err(|e| {
+                // Toml counts lines and columns from 0
+                let position = e.line_col().map(|(l, c)| (l + 1, c + 1));
+                let syntax = toml::from_str::<toml::Value>(content).is_err();
+                FreshgitError::parse(path, self.name(), e.to_string(), position, syntax)
+            }),
+            Format::Yaml => serde_yaml::from_str(content).map_err(|e| {
+                let position = e.location().map(|l| (l.line(), l.column()));
+                let syntax = serde_yaml::from_str::<serde_yaml::Value>(content).is_err();
+                FreshgitError::parse(path, self.name(), e.to_string(), position, syntax)
+            }),
+        }
+    }
+
+    /// Writes config values in this format.
+    fn write(self, value: &Value) -> Result<String, String> {
+        match self {
+            Format::Json => serde_json::to_string_pretty(value)
+                .map(|s| s + "\n")
+                .map_err(|e| e.to_string()),
+            Format::Toml => toml::Value::try_from(without_nulls(value.clone()))
+                .and_then(|tv| toml::to_string(&tv))
+                .map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Reads file at `path` in the format of its extension.
+pub fn read<T: DeserializeOwned>(path: &Path) -> Result<T, FreshgitError> {
+    let content = fs::read_to_string(path).map_err(|source| FreshgitError::ConfigRead {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    Format::from_path(path).parse(path, &content)
+}
+
+/// Converts config at `from` to the format of `to` extension. Config is checked
+/// first, so invalid config is never converted, and secret references are kept as
+/// they are. Existing file is never overwritten.
+pub fn convert(from: &Path, to: &Path) -> Result<(), FreshgitError> {
+    read::<Config>(from)?;
+    let value = read::<Value>(from)?;
+
+    let write_err = |message: String| FreshgitError::ConfigWrite {
+        path: to.to_path_buf(),
+        message,
+    };
+    let content = Format::from_path(to).write(&value).map_err(write_err)?;
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)
+        .and_then(|mut f| f.write_all(content.as_bytes()))
+        .map_err(|e| write_err(e.to_string()))
+}
+
+/// Removes fields set to `null`, toml has no way to write them and they mean the
+/// same as missing fields.
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(m) => Value::Object(
+            m.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(a) => Value::Array(a.into_iter().map(without_nulls).collect()),
+        v => v,
+    }
+}
+
+/// Config merged from all layers, with origin of every value.
+pub struct Layers {
+    value: Value,
+    /// Path of every value to the layer it was taken from.
+    origins: BTreeMap<Vec<String>, String>,
+    /// Files of the layers, from the lowest to the highest.
+    files: Vec<PathBuf>,
+}
+
+impl Layers {
+    /// Finds config files in the standard order and merges them, `explicit` is
+    /// the file of `-c/--config`. Missing explicit files are errors, missing files
+    /// of standard locations are skipped, so layers can be empty.
+    pub fn load(explicit: Option<&Path>) -> Result<Layers, FreshgitError> {
+        let mut layers = Layers {
+            value: Value::Object(Map::new()),
+            origins: BTreeMap::new(),
+            files: Vec::new(),
+        };
+        for f in discover(explicit)? {
+            // Every file is checked on its own, so errors point into the file
+            read::<Config>(&f)?;
+            let value = read::<Value>(&f)?;
+            merge(
+                &mut layers.value,
+                value,
+                &mut Vec::new(),
+                &f.display().to_string(),
+                &mut layers.origins,
+            );
+            layers.files.push(f);
+        }
+
+        Ok(layers)
+    }
+
+    /// Names of profiles defined in config files, sorted.
+    pub fn profiles(&self) -> Vec<String> {
+        match self.value.get(PROFILES) {
+            Some(Value::Object(ps)) => ps.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Takes values of `profile` on top of the values set outside of profiles.
+    /// Profiles are removed from merged values, so only base values are left if
+    /// `profile` is `None`.
+    pub fn select_profile(&mut self, profile: Option<&str>) -> Result<(), FreshgitError> {
+        let names = self.profiles();
+        let profiles = self
+            .value
+            .as_object_mut()
+            .and_then(|v| v.remove(PROFILES))
+            .unwrap_or(Value::Null);
+        let prefix = vec![PROFILES.to_string()];
+        let mut profile_origins = BTreeMap::<Vec<String>, String>::new();
+        self.origins.retain(|p, o| match p.starts_with(&prefix) {
+            true => {
+                profile_origins.insert(p[1..].to_vec(), o.clone());
+                false
+            }
+            false => true,
+        });
+
+        let name = match profile {
+            Some(n) => n,
+            None => return Ok(()),
+        };
+        let invalid = |message: String| FreshgitError::ConfigValue {
+            origin: None,
+            field: format!("{}.{}", PROFILES, name),
+            message,
+        };
+        let value = match profiles.get(name) {
+            Some(v) => v.clone(),
+            None if names.is_empty() => return Err(invalid("no profiles are defined".to_string())),
+            None => {
+                return Err(invalid(format!(
+                    "is not found, profiles are: {}",
+                    names.join(", ")
+                )))
+            }
+        };
+        if value.get(PROFILES).is_some() {
+            return Err(invalid("profiles can't be nested".to_string()));
+        }
+
+        merge(
+            &mut self.value,
+            value,
+            &mut Vec::new(),
+            "",
+            &mut self.origins,
+        );
+        // Every value keeps the file it was set in, like values outside of profiles
+        let prefix = vec![name.to_string()];
+        for (p, o) in profile_origins
+            .into_iter()
+            .filter(|(p, _)| p.starts_with(&prefix))
+        {
+            let path = p[1..].to_vec();
+            if self.origins.contains_key(&path) {
+                self.origins
+                    .insert(path, format!("{} (profile {})", o, name));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks whether no value is set, e.g. no config file is found.
+    pub fn is_empty(&self) -> bool {
+        self.origins.is_empty()
+    }
+
+    /// Sets value at `path` on top of all layers, e.g. from command line. The first
+    /// of `values` which is valid for the field is taken, error of the first one is
+    /// returned if none is valid. Value is checked together with values it's
+    /// merged with, e.g. `identity` of ssh key set in a file, unless they are
+    /// invalid by themselves, then they are reported when config is read.
+    pub fn set(&mut self, path: &[String], values: Vec<Value>, origin: &str) -> Result<(), String> {
+        let whole = serde_json::from_value::<Config>(self.value.clone()).is_ok();
+        let mut first_err = None;
+        for v in values {
+            let nested = path
+                .iter()
+                .rev()
+                .fold(v, |v, k| Value::Object(Map::from_iter([(k.clone(), v)])));
+            let mut value = self.value.clone();
+            let mut origins = self.origins.clone();
+            merge(
+                &mut value,
+                nested.clone(),
+                &mut Vec::new(),
+                origin,
+                &mut origins,
+            );
+            let checked = match whole {
+                true => value.clone(),
+                false => nested,
+            };
+            match serde_json::from_value::<Config>(checked) {
+                Ok(_) => {
+                    self.value = value;
+                    self.origins = origins;
+                    return Ok(());
+                }
+                Err(e) => {
+                    first_err.get_or_insert(e.to_string());
+                }
+            }
+        }
+
+        Err(first_err.unwrap_or("no value".to_string()))
+    }
+
+    /// Merged config.
+    pub fn config(&self) -> Result<Config, FreshgitError> {
+        serde_json::from_value(self.value.clone()).map_err(|e| {
+            FreshgitError::parse(
+                self.top().unwrap_or(Path::new("")),
+                "config",
+                e.to_string(),
+                None,
+                false,
+            )
+        })
+    }
+
+    /// File of the highest layer, `None` if values are only set by overrides.
+    pub fn top(&self) -> Option<&Path> {
+        self.files.last().map(|f| f.as_path())
+    }
+
+    /// Layer the value at dotted path was taken from, e.g. `hosts.<host>.max_jobs`.
+    pub fn origin(&self, path: &[&str]) -> Option<&str> {
+        let path: Vec<String> = path.iter().map(|p| p.to_string()).collect();
+        self.origins.get(&path).map(|o| o.as_str())
+    }
+
+    /// Prints merged values one per line, with the layer every value was taken
+    /// from if `origin` is set. Secrets are never printed.
+    pub fn show(&self, origin: bool) {
+        for f in self.files.iter() {
+            println!("# {}", f.display());
+        }
+        for (path, o) in self.origins.iter() {
+            let value = path.iter().try_fold(&self.value, |v, k| v.get(k));
+            let shown = match (path.last(), value) {
+                (Some(k), _) if SECRET_KEYS.contains(&k.as_str()) => "\"<redacted>\"".to_string(),
+                (_, Some(v)) => secret::redact_urls(&v.to_string()),
+                (_, None) => continue,
+            };
+            match origin {
+                true => println!("{} = {}  # {}", key_path(path), shown, o),
+                false => println!("{} = {}", key_path(path), shown),
+            }
+        }
+    }
+}
+
+/// Existing config files from the lowest layer to the highest.
+fn discover(explicit: Option<&Path>) -> Result<Vec<PathBuf>, FreshgitError> {
+    let mut files = Vec::<PathBuf>::new();
+    files.extend(find_config(Path::new(SYSTEM_DIR)));
+    let user_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or(env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")));
+    if let Some(d) = user_dir {
+        files.extend(find_config(&d.join("freshgit")));
+    }
+    let local = PathBuf::from(LOCAL_CONFIG);
+    if local.is_file() {
+        files.push(local);
+    }
+
+    // Files named explicitly have to exist
+    let named = env::var_os(ENV_CONFIG)
+        .filter(|c| !c.is_empty())
+        .map(PathBuf::from)
+        .into_iter()
+        .chain(explicit.map(|e| e.to_path_buf()));
+    for f in named {
+        if !f.is_file() {
+            return Err(FreshgitError::ConfigRead {
+                path: f,
+                source: io::Error::new(io::ErrorKind::NotFound, "file doesn't exist"),
+            });
+        }
+        if !files.contains(&f) {
+            files.push(f);
+        }
+    }
+
+    Ok(files)
+}
+
+/// First `config.<ext>` of the folder by order of `EXTENSIONS`.
+fn find_config(dir: &Path) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|e| dir.join(CONFIG_NAME).with_extension(e))
+        .find(|f| f.is_file())
+}
+
+/// Merges `value` into `base`, tables are merged key by key and other values
+/// replace the base. Nulls are the same as missing values. Origin of every
+/// replaced value is set to `origin`.
+fn merge(
+    base: &mut Value,
+    value: Value,
+    path: &mut Vec<String>,
+    origin: &str,
+    origins: &mut BTreeMap<Vec<String>, String>,
+) {
+    match (base, value) {
+        (Value::Object(b), Value::Object(v)) => {
+            for (k, v) in v.into_iter().filter(|(_, v)| !v.is_null()) {
+                path.push(k.clone());
+                let entry = b.entry(k).or_insert(Value::Null);
+                if !(entry.is_object() && v.is_object()) {
+                    // Values of the lower layer under this path are gone
+                    origins.retain(|p, _| !p.starts_with(path));
+                    *entry = Value::Object(Map::new());
+                }
+                match v.is_object() {
+                    true => merge(entry, v, path, origin, origins),
+                    false => {
+                        *entry = v;
+                        origins.insert(path.clone(), origin.to_string());
+                    }
+                }
+                path.pop();
+            }
+        }
+        (b, v) => *b = v,
+    }
+}
+
+/// Dotted path of the value, keys which are not plain words are quoted, e.g.
+/// `hosts."github.com".max_jobs`.
+fn key_path(path: &[String]) -> String {
+    path.iter()
+        .map(|k| {
+            match k
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                true => k.clone(),
+                false => format!("{:?}", k),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(p: &[&str]) -> Vec<String> {
+        p.iter().map(|k| k.to_string()).collect()
+    }
+
+    fn layers(files: &[(&str, Value)]) -> Layers {
+        let mut layers = Layers {
+            value: Value::Object(Map::new()),
+            origins: BTreeMap::new(),
+            files: Vec::new(),
+        };
+        for (f, v) in files {
+            merge(
+                &mut layers.value,
+                v.clone(),
+                &mut Vec::new(),
+                f,
+                &mut layers.origins,
+            );
+            layers.files.push(PathBuf::from(f));
+        }
+        layers
+    }
+
+    #[test]
+    fn merges_tables_key_by_key() {
+        let l = layers(&[
+            (
+                "system",
+                json!({
+                    "max_jobs": 8,
+                    "src_folder": "/srv",
+                    "hosts": { "github.com": { "max_jobs": 4, "delay_ms": 100 } }
+                }),
+            ),
+            (
+                "user",
+                json!({
+                    "max_jobs": 2,
+                    "src_folder": null,
+                    "hosts": {
+                        "github.com": { "max_jobs": 1 },
+                        "gitlab.com": { "max_jobs": 3 }
+                    }
+                }),
+            ),
+        ]);
+
+        assert_eq!(
+            l.value,
+            json!({
+                "max_jobs": 2,
+                "src_folder": "/srv",
+                "hosts": {
+                    "github.com": { "max_jobs": 1, "delay_ms": 100 },
+                    "gitlab.com": { "max_jobs": 3 }
+                }
+            })
+        );
+        assert_eq!(l.origin(&["max_jobs"]), Some("user"));
+        assert_eq!(l.origin(&["src_folder"]), Some("system"));
+        assert_eq!(l.origin(&["hosts", "github.com", "max_jobs"]), Some("user"));
+        assert_eq!(
+            l.origin(&["hosts", "github.com", "delay_ms"]),
+            Some("system")
+        );
+        assert_eq!(l.top(), Some(Path::new("user")));
+    }
+
+    #[test]
+    fn replaces_table_with_other_value() {
+        let l = layers(&[
+            ("a", json!({ "repo_timeouts": { "x": 1, "y": 2 } })),
+            ("b", json!({ "repo_timeouts": 5 })),
+            ("c", json!({ "repo_timeouts": { "z": 3 } })),
+        ]);
+
+        assert_eq!(l.value, json!({ "repo_timeouts": { "z": 3 } }));
+        assert_eq!(l.origins.len(), 1);
+        assert_eq!(l.origin(&["repo_timeouts", "z"]), Some("c"));
+    }
+
+    #[test]
+    fn quotes_key_paths() {
+        assert_eq!(
+            key_path(&path(&["hosts", "github.com", "max_jobs"])),
+            "hosts.\"github.com\".max_jobs"
+        );
+        assert_eq!(key_path(&path(&["src_folder"])), "src_folder");
+    }
+
+    #[test]
+    fn sets_only_valid_values() {
+        let mut l = layers(&[(
+            "file",
+            json!({ "hosts": { "github.com": { "delay_ms": 100 } } }),
+        )]);
+        l.set(
+            &path(&["hosts", "github.com", "max_jobs"]),
+            vec![json!(2)],
+            "--set",
+        )
+        .unwrap();
+        assert_eq!(
+            l.value["hosts"]["github.com"],
+            json!({ "delay_ms": 100, "max_jobs": 2 })
+        );
+        assert_eq!(
+            l.origin(&["hosts", "github.com", "max_jobs"]),
+            Some("--set")
+        );
+
+        // The first valid value is taken
+        l.set(
+            &path(&["git_username"]),
+            vec![json!(1234), json!("1234")],
+            "--set",
+        )
+        .unwrap();
+        assert_eq!(l.value["git_username"], json!("1234"));
+
+        assert!(l
+            .set(&path(&["max_jobs"]), vec![json!("many")], "--set")
+            .is_err());
+        assert!(l.set(&path(&["max_job"]), vec![json!(1)], "--set").is_err());
+        assert_eq!(l.value.get("max_jobs"), None);
+    }
+
+    #[test]
+    fn selects_profile() {
+        let mut l = layers(&[(
+            "freshgit.toml",
+            json!({
+                "max_jobs": 8,
+                "src_folder": "/base",
+                "profiles": {
+                    "oss": { "src_folder": "/oss" },
+                    "work": { "max_jobs": 2 }
+                }
+            }),
+        )]);
+        assert_eq!(l.profiles(), path(&["oss", "work"]));
+
+        l.select_profile(Some("oss")).unwrap();
+        assert_eq!(l.value, json!({ "max_jobs": 8, "src_folder": "/oss" }));
+        assert_eq!(
+            l.origin(&["src_folder"]),
+            Some("freshgit.toml (profile oss)")
+        );
+        assert_eq!(l.origin(&["max_jobs"]), Some("freshgit.toml"));
+        assert!(l.profiles().is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_profile() {
+        let mut l = layers(&[("f", json!({ "profiles": { "oss": {} } }))]);
+        let e = l.select_profile(Some("work")).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Config value profiles.work: is not found, profiles are: oss"
+        );
+    }
+
+    #[test]
+    fn tells_syntax_errors_from_wrong_values() {
+        let syntax = |file: &str, content: &str| match Format::from_path(Path::new(file))
+            .parse::<Config>(Path::new(file), content)
+        {
+            Err(FreshgitError::ConfigParse { syntax, .. }) => syntax,
+            _ => panic!("{} is parsed: {}", file, content),
+        };
+        assert!(syntax("c.json", "{\"max_jobs\": }"));
+        assert!(!syntax("c.json", "{\"max_jobs\": \"x\"}"));
+        assert!(syntax("c.toml", "max_jobs = "));
+        assert!(!syntax("c.toml", "max_jobs = \"x\""));
+        assert!(!syntax("c.toml", "max_jobz = 1"));
+        assert!(syntax("c.yaml", "max_jobs: [1"));
+        assert!(!syntax("c.yaml", "max_jobs: x"));
+    }
+
+    #[test]
+    fn sets_values_merged_with_files() {
+        let mut l = layers(&[(
+            "file",
+            json!({ "hosts": { "h": { "ssh_key": { "identity": "~/.ssh/id" } } } }),
+        )]);
+        l.set(
+            &path(&["hosts", "h", "ssh_key", "options"]),
+            vec![json!(["Port=2"])],
+            "--set",
+        )
+        .unwrap();
+        assert_eq!(
+            l.value["hosts"]["h"]["ssh_key"],
+            json!({ "identity": "~/.ssh/id", "options": ["Port=2"] })
+        );
+
+        // Values of files are checked when config is read
+        let mut l = layers(&[("file", json!({ "max_jobs": "many" }))]);
+        l.set(&path(&["git_username"]), vec![json!("u")], "--set")
+            .unwrap();
+        assert_eq!(l.origin(&["git_username"]), Some("--set"));
+        assert!(l.config().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! Resolves https credentials of remote hosts with git credential helpers, so
+//! passwords don't have to be stored in config, and tokens of hosts. Credentials
+//! are looked up once per host and are only passed to git through environment,
+//! they are never logged or passed as arguments.
+use crate::dl_upd::HostConfig;
+use crate::git_ops::{kill_process_group, new_process_group, Running};
+use crate::remote::RemoteAddr;
+use crate::secret::{self, Secret};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::{Mutex, OnceCell};
+
+/// Helper name telling to use credential helpers configured in git itself.
+const GIT_HELPERS: &str = "git";
+/// Schemes credential helpers are used for.
+const HTTP_SCHEMES: [&str; 2] = ["http", "https"];
+/// Scheme tokens are sent to, plain http would expose them to the network.
+const TOKEN_SCHEME: &str = "https";
+/// Username sent with token if host doesn't set `token_username`.
+const TOKEN_USERNAME: &str = "x-access-token";
+/// Time credential helper has to answer in, helpers waiting for input, e.g. for
+/// pinentry of gpg, are stopped after it.
+const FILL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Username and password of the remote host.
+pub struct Credential {
+    pub username: String,
+    pub password: Secret,
+}
+
+/// Resolved credentials by scheme and host, failed lookups are kept too, so helper
+/// is not asked again for every repository. Every host has its own cell, so slow
+/// helper of one host doesn't hold back other hosts.
+type Cache = HashMap<(String, String), Arc<OnceCell<Option<Arc<Credential>>>>>;
+
+/// Credential helper, username and token of a single host.
+struct HostHelper {
+    helper: Option<String>,
+    username: Option<String>,
+    token: Option<Secret>,
+    token_username: Option<String>,
+}
+
+pub struct Credentials {
+    helper: Option<String>,
+    hosts: HashMap<String, HostHelper>,
+    cache: Mutex<Cache>,
+}
+
+impl Credentials {
+    /// Creates resolver using `helper` for all hosts, hosts can override it and
+    /// pick username with `credential_helper` and `username` of their entries.
+    /// `git` as helper means credential helpers configured in git are used.
+    pub fn new(helper: Option<String>, hosts: &HashMap<String, HostConfig>) -> Credentials {
+        let hosts = hosts
+            .iter()
+            .map(|(h, hc)| {
+                let hh = HostHelper {
+                    helper: hc.credential_helper.clone(),
+                    username: hc.username.clone(),
+                    token: hc.token.clone().filter(|t| !t.is_empty()),
+                    token_username: hc.token_username.clone(),
+                };
+                (h.to_lowercase(), hh)
+            })
+            .collect();
+
+        Credentials {
+            helper,
+            hosts,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Finds credentials of http(s) remote, `None` if no helper is configured for
+    /// its host, helper doesn't know it or token of the host is sent to it.
+    pub async fn get(&self, ra: &RemoteAddr) -> Option<Arc<Credential>> {
+        let scheme = ra.scheme().filter(|s| HTTP_SCHEMES.contains(s))?;
+        let hh = self.hosts.get(ra.host()?);
+        if scheme == TOKEN_SCHEME && hh.is_some_and(|hh| hh.token.is_some()) {
+            return None;
+        }
+        let helper = hh
+            .and_then(|hh| hh.helper.as_ref())
+            .or(self.helper.as_ref())?;
+        let username = hh.and_then(|hh| hh.username.as_deref());
+
+        let host = host_port(ra)?;
+        let cell = self
+            .cache
+            .lock()
+            .await
+            .entry((scheme.to_string(), host.clone()))
+            .or_default()
+            .clone();
+
+        // Helper is asked only once for every host, other repositories of the host
+        // wait for its answer
+        cell.get_or_init(|| async {
+            let cred = fill(helper, scheme, &host, username).await.map(Arc::new);
+            match &cred {
+                Some(_) => info!("Credentials of {} are provided by credential helper", host),
+                None => warn!("Credential helper has no credentials of {}", host),
+            }
+            cred
+        })
+        .await
+        .clone()
+    }
+
+    /// Checks whether git run for the remote uses credential helpers configured in
+    /// git itself, `None` is remote of unknown host.
+    pub fn uses_git_helpers(&self, ra: Option<&RemoteAddr>) -> bool {
+        let hh = ra.and_then(|ra| self.hosts.get(ra.host()?));
+        let helper = hh
+            .and_then(|hh| hh.helper.as_deref())
+            .or(self.helper.as_deref());
+        helper == Some(GIT_HELPERS)
+    }
+
+    /// Http header with token of https remote, as git config key limited to the
+    /// host and its value. `None` if host has no token or remote is not https.
+    pub fn token_header(&self, ra: &RemoteAddr) -> Option<(String, Secret)> {
+        let scheme = ra.scheme().filter(|s| *s == TOKEN_SCHEME)?;
+        let hh = self.hosts.get(ra.host()?)?;
+        let token = hh.token.as_ref()?;
+        let username = hh.token_username.as_deref().unwrap_or(TOKEN_USERNAME);
+
+        let key = format!("http.{}://{}/.extraHeader", scheme, host_port(ra)?);
+        let auth = secret::base64(format!("{}:{}", username, token.expose()).as_bytes());
+        Some((key, Secret::new(format!("Authorization: Basic {}", auth))))
+    }
+}
+
+/// Host of the remote with port, if it's not the default one.
+fn host_port(ra: &RemoteAddr) -> Option<String> {
+    let host = ra.host()?;
+    match ra.port() {
+        Some(p) => Some(format!("{}:{}", host, p)),
+        None => Some(host.to_string()),
+    }
+}
+
+/// Runs `git credential fill` with the helper, git itself never prompts for
+/// missing credentials here. Helper which doesn't answer in time is killed with
+/// its children.
+async fn fill(
+    helper: &str,
+    scheme: &str,
+    host: &str,
+    username: Option<&str>,
+) -> Option<Credential> {
+    let mut cmd = Command::new("git");
+    if helper != GIT_HELPERS {
+        // Empty value resets helpers configured in git
+        cmd.arg("-c")
+            .arg("credential.helper=")
+            .arg("-c")
+            .arg(format!("credential.helper={}", helper));
+    }
+    cmd.args(["credential", "fill"])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GIT_ASKPASS", "")
+        .env("GCM_INTERACTIVE", "never")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+    new_process_group(&mut cmd);
+    let mut child = match cmd.spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("Could not run git credential: {}", e);
+            return None;
+        }
+    };
+
+    let mut input = format!("protocol={}\nhost={}\n", scheme, host);
+    if let Some(u) = username {
+        input.push_str(&format!("username={}\n", u));
+    }
+    input.push('\n');
+    let pid = child.id().unwrap_or_default();
+    let _running = Running::new(pid, None);
+    let mut stdin = child.stdin.take()?;
+    let answer = async {
+        stdin.write_all(input.as_bytes()).await.ok()?;
+        drop(stdin);
+        child.wait_with_output().await.ok()
+    };
+
+    let out = match tokio::time::timeout(FILL_TIMEOUT, answer).await {
+        Ok(out) => out?,
+        Err(_) => {
+            warn!(
+                "Credential helper didn't answer in {}s for {}",
+                FILL_TIMEOUT.as_secs(),
+                host
+            );
+            kill_process_group(pid);
+            return None;
+        }
+    };
+    if !out.status.success() {
+        return None;
+    }
+
+    let out = String::from_utf8_lossy(&out.stdout);
+    let value = |key: &str| {
+        out.lines()
+            .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+            .map(|v| v.to_string())
+    };
+
+    Some(Credential {
+        username: value("username")?,
+        password: Secret::new(value("password")?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(helper: Option<&str>) -> HostConfig {
+        HostConfig {
+            credential_helper: helper.map(|h| h.to_string()),
+            ..HostConfig::default()
+        }
+    }
+
+    fn remote(addr: &str) -> RemoteAddr {
+        RemoteAddr::parse(addr).unwrap()
+    }
+
+    fn token_host(token_username: Option<&str>) -> HostConfig {
+        HostConfig {
+            token: Some(Secret::new("tok".to_string())),
+            token_username: token_username.map(|u| u.to_string()),
+            ..HostConfig::default()
+        }
+    }
+
+    #[test]
+    fn sends_token_to_https_host() {
+        let hosts = HashMap::from([
+            ("host.com".to_string(), token_host(None)),
+            ("other.com".to_string(), token_host(Some("oauth2"))),
+        ]);
+        let cr = Credentials::new(None, &hosts);
+        let header = |addr| {
+            cr.token_header(&remote(addr))
+                .map(|(k, v)| (k, v.expose().to_string()))
+        };
+
+        assert_eq!(
+            header("https://host.com/o/r.git"),
+            Some((
+                "http.https://host.com/.extraHeader".to_string(),
+                format!(
+                    "Authorization: Basic {}",
+                    secret::base64(b"x-access-token:tok")
+                )
+            ))
+        );
+        assert_eq!(
+            header("https://other.com:8443/o/r.git"),
+            Some((
+                "http.https://other.com:8443/.extraHeader".to_string(),
+                format!("Authorization: Basic {}", secret::base64(b"oauth2:tok"))
+            ))
+        );
+    }
+
+    #[test]
+    fn doesnt_send_token_to_other_remotes() {
+        let hosts = HashMap::from([("host.com".to_string(), token_host(None))]);
+        let cr = Credentials::new(None, &hosts);
+        for addr in [
+            "http://host.com/o/r.git",
+            "git@host.com:o/r.git",
+            "ssh://git@host.com/o/r.git",
+            "https://unknown.com/o/r.git",
+        ] {
+            assert!(cr.token_header(&remote(addr)).is_none(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn uses_git_helpers_only_when_asked() {
+        let hosts = HashMap::from([
+            ("git.com".to_string(), host(Some(GIT_HELPERS))),
+            ("store.com".to_string(), host(Some("store"))),
+            ("plain.com".to_string(), host(None)),
+        ]);
+        let cr = Credentials::new(None, &hosts);
+        assert!(cr.uses_git_helpers(Some(&remote("https://git.com/o/r"))));
+        assert!(!cr.uses_git_helpers(Some(&remote("https://store.com/o/r"))));
+        assert!(!cr.uses_git_helpers(Some(&remote("https://plain.com/o/r"))));
+        assert!(!cr.uses_git_helpers(None));
+
+        let cr = Credentials::new(Some(GIT_HELPERS.to_string()), &hosts);
+        assert!(!cr.uses_git_helpers(Some(&remote("https://store.com/o/r"))));
+        assert!(cr.uses_git_helpers(Some(&remote("https://plain.com/o/r"))));
+        assert!(cr.uses_git_helpers(None));
+    }
+}
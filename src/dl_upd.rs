@@ -1,17 +1,20 @@
-//! This module is processing Config from .json file to ensure data is valid before passing
+//! This module is processing Config from .json, .toml or .yaml file to ensure data is valid before passing
 //! it to update(fetch) and download(clone) git functions.
 
+use crate::config_file::Layers;
+use crate::error::FreshgitError;
 use crate::git_ops::{git_config_and_run, GitMode};
+use crate::overrides;
+use crate::report::Summary;
+use crate::secret::{self, Secret};
 use clap::ArgMatches;
 use core::fmt;
 use lazy_static::lazy_static;
 use log::{error, info};
 use serde::Deserialize;
-use serde_json;
 use std::{
+    collections::HashMap,
     fmt::Display,
-    fs::File,
-    io::BufReader,
     path::{Path, PathBuf},
     sync::RwLock,
 };
@@ -34,32 +37,134 @@ const GPASS: &str = "Git password:";
 const SPASS: &str = "SSH askpass:";
 /// Async exec formatting str
 const AEXEC: &str = "Async execution:";
+/// CSV column formatting str
+const CSVCL: &str = "CSV column:";
+/// Max jobs formatting str
+const MJOBS: &str = "Max jobs:";
+/// Hosts formatting str
+const HOSTS: &str = "Hosts:";
+/// Timeout formatting str
+const TMOUT: &str = "Timeout, s:";
+/// Stall timeout formatting str
+const STALL: &str = "Stall timeout, s:";
+/// Repository timeouts formatting str
+const RTOUT: &str = "Repository timeouts, s:";
+/// Retries formatting str
+const RETRS: &str = "Retries:";
+/// Retry delay formatting str
+const RDLAY: &str = "Retry delay, ms:";
+/// Credential helper formatting str
+const CHELP: &str = "Credential helper:";
+/// Profile formatting str
+const PROFL: &str = "Profile:";
 
 /// Passes actual config data to update/fetch function.
-pub fn update_directories(matches: ArgMatches) {
-    update_config(&matches);
-    let conf = get_config();
-    info!("Configuration: {}", conf);
-    git_config_and_run(conf, GitMode::FETCH);
+pub fn update_directories(matches: ArgMatches) -> Result<Summary, FreshgitError> {
+    run_profiles(&matches, GitMode::FETCH)
 }
 
 /// Passes actual config data to download/clone function.
-pub fn download_repos(matches: ArgMatches) {
-    update_config(&matches);
-    let conf = get_config();
-    info!("Configuration: {}", conf);
-    git_config_and_run(conf, GitMode::CLONE);
+pub fn download_repos(matches: ArgMatches) -> Result<Summary, FreshgitError> {
+    run_profiles(&matches, GitMode::CLONE)
+}
+
+/// Runs git with the profile of `--profile`, or with every profile in turn for
+/// `--all-profiles`, combining their outcomes. Configs of all profiles are read
+/// before the first one is run, so nothing is run if any of them is invalid.
+fn run_profiles(matches: &ArgMatches, mode: GitMode) -> Result<Summary, FreshgitError> {
+    let profiles = match matches.is_present("all-profiles") {
+        true => {
+            let names = Layers::load(matches.value_of("config").map(Path::new))?.profiles();
+            if names.is_empty() {
+                return Err(FreshgitError::ConfigValue {
+                    origin: None,
+                    field: "profiles".to_string(),
+                    message: "no profiles are defined".to_string(),
+                });
+            }
+            names.into_iter().map(Some).collect()
+        }
+        false => vec![matches.value_of("profile").map(|p| p.to_string())],
+    };
+    let confs = profiles
+        .iter()
+        .map(|p| read_config(matches, p.as_deref()))
+        .collect::<Result<Vec<Config>, FreshgitError>>()?;
+
+    let mut summary = Summary::default();
+    for conf in confs {
+        let profile = conf.profile.clone();
+        update_config(conf)?;
+        let conf = get_config()?;
+        info!("Configuration: {}", conf);
+        match (git_config_and_run(conf, mode), profile) {
+            (Some(s), p) => summary.add(p.as_deref(), s),
+            (None, Some(p)) if profiles.len() > 1 => {
+                error!("Profile {} was aborted because of config problems", p);
+                summary.aborted.push(p);
+            }
+            (None, _) => return Err(FreshgitError::Aborted),
+        }
+    }
+
+    Ok(summary)
 }
 
 #[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub config_path: Option<PathBuf>,
     pub src_folder: Option<PathBuf>,
     pub files_to_read: Option<Vec<PathBuf>>,
     pub git_username: Option<String>,
-    pub git_password: Option<String>,
-    pub ssh_askpass: Option<String>,
+    pub git_password: Option<Secret>,
+    pub ssh_askpass: Option<Secret>,
     pub async_exec: Option<bool>,
+    pub csv_column: Option<String>,
+    pub max_jobs: Option<usize>,
+    pub hosts: Option<HashMap<String, HostConfig>>,
+    pub timeout_secs: Option<u64>,
+    pub stall_timeout_secs: Option<u64>,
+    pub repo_timeouts: Option<HashMap<String, u64>>,
+    pub retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub credential_helper: Option<String>,
+    /// Profile the config was taken from.
+    #[serde(skip_deserializing)]
+    pub profile: Option<String>,
+    /// Named configs overriding values of this one, only read from config files.
+    pub profiles: Option<HashMap<String, Config>>,
+}
+
+/// Limits and credentials applied to every repository of the remote host.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// Amount of git processes to run for this host at once.
+    pub max_jobs: Option<usize>,
+    /// Minimal delay between starts of git processes for this host.
+    pub delay_ms: Option<u64>,
+    /// Username passed to credential helper, for hosts with several accounts.
+    pub username: Option<String>,
+    /// Credential helper for this host, overrides `credential_helper` of config.
+    pub credential_helper: Option<String>,
+    /// Token sent to https remotes of this host in http header.
+    pub token: Option<Secret>,
+    /// Username sent with the token.
+    pub token_username: Option<String>,
+    /// SSH key used for repositories of this host.
+    pub ssh_key: Option<SshKey>,
+    /// SSH keys used for repositories of owners, e.g. `org` or `org/team`, instead
+    /// of `ssh_key`, the longest matching owner is used.
+    pub owner_ssh_keys: Option<HashMap<String, SshKey>>,
+}
+
+/// SSH identity file with additional ssh options, e.g. `Port=2222`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SshKey {
+    pub identity: PathBuf,
+    pub options: Option<Vec<String>>,
 }
 
 impl Default for Config {
@@ -69,9 +174,20 @@ impl Default for Config {
             src_folder: Some(PathBuf::with_capacity(256)),
             files_to_read: Some(Vec::<PathBuf>::with_capacity(16)),
             git_username: Some(String::with_capacity(16)),
-            git_password: Some(String::with_capacity(16)),
-            ssh_askpass: Some(String::with_capacity(16)),
+            git_password: Some(Secret::default()),
+            ssh_askpass: Some(Secret::default()),
             async_exec: Some(false),
+            csv_column: Some(String::from("repository")),
+            max_jobs: Some(8),
+            hosts: Some(HashMap::new()),
+            timeout_secs: None,
+            stall_timeout_secs: None,
+            repo_timeouts: Some(HashMap::new()),
+            retries: Some(2),
+            retry_delay_ms: Some(1000),
+            credential_helper: None,
+            profile: None,
+            profiles: None,
         }
     }
 }
@@ -80,82 +196,179 @@ impl Display for Config {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}: {:#?} {}: {:#?} {}: {:#?} {}: {} {}: {} {}: {} {}: {}",
+            "{}: {:#?} {}: {:#?} {}: {:#?} {}: {} {}: {} {}: {} {}: {} {}: {} {}: {:?} {}: {:?} {}: {:?} {}: {:?} {}: {:?} {}: {:?} {}: {:?} {}: {:?} {}: {:?}",
             CPATH,
-            self.config_path.clone().unwrap(),
+            self.config_path.clone().unwrap_or_default(),
             SFOLD,
-            self.src_folder.clone().unwrap(),
+            self.src_folder.clone().unwrap_or_default(),
             FLTRD,
-            self.files_to_read.clone().unwrap(),
+            self.files_to_read.clone().unwrap_or_default(),
             GUSER,
-            self.git_username.clone().unwrap(),
+            self.git_username.clone().unwrap_or_default(),
             GPASS,
-            self.git_password.clone().unwrap(),
+            self.git_password.clone().unwrap_or_default(),
             SPASS,
-            self.ssh_askpass.clone().unwrap(),
+            self.ssh_askpass.clone().unwrap_or_default(),
             AEXEC,
-            self.async_exec.clone().unwrap()
+            self.async_exec.unwrap_or_default(),
+            CSVCL,
+            self.csv_column.clone().unwrap_or_default(),
+            MJOBS,
+            self.max_jobs,
+            HOSTS,
+            self.hosts.clone().unwrap_or_default(),
+            TMOUT,
+            self.timeout_secs,
+            STALL,
+            self.stall_timeout_secs,
+            RTOUT,
+            self.repo_timeouts.clone().unwrap_or_default(),
+            RETRS,
+            self.retries,
+            RDLAY,
+            self.retry_delay_ms,
+            CHELP,
+            self.credential_helper,
+            PROFL,
+            self.profile
         )
     }
 }
 
 /// Reads config data and clones it to pass to another functions.
-pub fn get_config() -> Config {
-    let conf = &CONFIG.read().unwrap();
-    Config {
+pub fn get_config() -> Result<Config, FreshgitError> {
+    let conf = &CONFIG.read().map_err(|_| FreshgitError::ConfigLock)?;
+    Ok(Config {
         config_path: conf.config_path.clone(),
         src_folder: conf.src_folder.clone(),
         files_to_read: conf.files_to_read.clone(),
         git_username: conf.git_username.clone(),
         git_password: conf.git_password.clone(),
         ssh_askpass: conf.ssh_askpass.clone(),
-        async_exec: conf.async_exec.clone(),
-    }
+        async_exec: conf.async_exec,
+        csv_column: conf.csv_column.clone(),
+        max_jobs: conf.max_jobs,
+        hosts: conf.hosts.clone(),
+        timeout_secs: conf.timeout_secs,
+        stall_timeout_secs: conf.stall_timeout_secs,
+        repo_timeouts: conf.repo_timeouts.clone(),
+        retries: conf.retries,
+        retry_delay_ms: conf.retry_delay_ms,
+        credential_helper: conf.credential_helper.clone(),
+        profile: conf.profile.clone(),
+        profiles: conf.profiles.clone(),
+    })
 }
 
-fn update_config(matches: &ArgMatches) {
+/// Replaces global config with config read by `read_config`.
+fn update_config(uconf: Config) -> Result<(), FreshgitError> {
     // debug!("Unlocking config");
-    let upd = &mut CONFIG.write().unwrap();
-    upd.config_path = Some(PathBuf::from(matches.value_of("config").unwrap()));
-    let uconf = read_config(matches);
+    let upd = &mut CONFIG.write().map_err(|_| FreshgitError::ConfigLock)?;
+    upd.config_path = uconf.config_path;
     upd.src_folder = uconf.src_folder;
     upd.files_to_read = uconf.files_to_read;
     upd.git_username = uconf.git_username;
     upd.git_password = uconf.git_password;
     upd.ssh_askpass = uconf.ssh_askpass;
     upd.async_exec = uconf.async_exec;
+    upd.csv_column = uconf.csv_column;
+    upd.max_jobs = uconf.max_jobs;
+    upd.hosts = uconf.hosts;
+    upd.timeout_secs = uconf.timeout_secs;
+    upd.stall_timeout_secs = uconf.stall_timeout_secs;
+    upd.repo_timeouts = uconf.repo_timeouts;
+    upd.retries = uconf.retries;
+    upd.retry_delay_ms = uconf.retry_delay_ms;
+    upd.credential_helper = uconf.credential_helper;
+    upd.profile = uconf.profile;
+    upd.profiles = uconf.profiles;
+
+    Ok(())
 }
 
-fn read_config(matches: &ArgMatches) -> Config {
-    let filep = Path::new(matches.value_of("config").unwrap());
-    let content = read_json(filep);
+/// Reads config merged from standard locations and the file passed with
+/// `-c/--config`, takes values of `profile` on top of it, applies overrides of
+/// environment and command line, and resolves its secrets.
+pub fn read_config(matches: &ArgMatches, profile: Option<&str>) -> Result<Config, FreshgitError> {
+    let mut layers = Layers::load(matches.value_of("config").map(Path::new))?;
+    layers.select_profile(profile)?;
+    overrides::apply(&mut layers, matches)?;
+    if layers.is_empty() {
+        return Err(FreshgitError::ConfigMissing);
+    }
+    let content = layers.config()?;
+    check_values(&layers, &content)?;
+
+    let mut hosts = content.hosts;
+    for (h, hc) in hosts.iter_mut().flatten() {
+        hc.token = read_secret(&format!("token of {}", h), hc.token.take())?;
+    }
 
-    return Config {
-        config_path: content.config_path,
+    Ok(Config {
+        config_path: layers.top().map(|t| t.to_path_buf()),
         src_folder: content.src_folder,
         files_to_read: content.files_to_read,
         git_username: content.git_username,
-        git_password: content.git_password,
-        ssh_askpass: content.ssh_askpass,
+        git_password: read_secret("git_password", content.git_password)?,
+        ssh_askpass: read_secret("ssh_askpass", content.ssh_askpass)?,
         async_exec: content.async_exec,
-    };
+        csv_column: content.csv_column,
+        max_jobs: content.max_jobs,
+        hosts,
+        timeout_secs: content.timeout_secs,
+        stall_timeout_secs: content.stall_timeout_secs,
+        repo_timeouts: content.repo_timeouts,
+        retries: content.retries,
+        retry_delay_ms: content.retry_delay_ms,
+        credential_helper: content.credential_helper,
+        profile: profile.map(|p| p.to_string()),
+        profiles: None,
+    })
 }
 
-fn read_json<P: AsRef<Path>>(path: P) -> Config {
-    let file = match File::open(path) {
-        Ok(f) => f,
-        Err(e) => {
-            error!("Could not read file: {}", e);
-            panic!("Could not read config file!");
-        }
+/// Resolves `${env:NAME}`, `file:/path` and `cmd:command` references of secret
+/// config fields.
+fn read_secret(field: &str, value: Option<Secret>) -> Result<Option<Secret>, FreshgitError> {
+    value
+        .map(|v| secret::resolve(v.expose()).map(Secret::new))
+        .transpose()
+        .map_err(|message| FreshgitError::Secret {
+            field: field.to_string(),
+            message,
+        })
+}
+
+/// Checks values serde can't, so config which can't work is rejected before git
+/// is run.
+fn check_values(layers: &Layers, conf: &Config) -> Result<(), FreshgitError> {
+    let invalid = |path: &[&str], message: &str| FreshgitError::ConfigValue {
+        origin: layers.origin(path).map(|o| o.to_string()),
+        field: path.join("."),
+        message: message.to_string(),
     };
-    let reader = BufReader::new(file);
-    let conf = serde_json::from_reader(reader);
-    match conf {
-        Ok(c) => return c,
-        Err(e) => {
-            error!("Could not deserialize .json: {}", e);
-            panic!("Could not deserialize .json");
+
+    if conf
+        .src_folder
+        .as_ref()
+        .is_none_or(|sf| sf.as_os_str().is_empty())
+    {
+        return Err(invalid(&["src_folder"], "has to be set"));
+    }
+    if conf
+        .csv_column
+        .as_ref()
+        .is_some_and(|cc| cc.trim().is_empty())
+    {
+        return Err(invalid(&["csv_column"], "can't be empty"));
+    }
+    if conf.max_jobs == Some(0) {
+        return Err(invalid(&["max_jobs"], "has to be at least 1"));
+    }
+    for (h, hc) in conf.hosts.iter().flatten() {
+        if hc.max_jobs == Some(0) {
+            return Err(invalid(&["hosts", h, "max_jobs"], "has to be at least 1"));
         }
     }
+
+    Ok(())
 }
@@ -0,0 +1,254 @@
+//! Errors which stop freshgit before any repository is processed, each of them is
+//! printed as a single message and has its own exit code.
+use core::fmt;
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Start of serde message about key which is not a field of config.
+const UNKNOWN_FIELD: &str = "unknown field `";
+/// Table of unknown key in toml messages.
+const FOR_KEY: &str = " for key ";
+/// Minimal similarity of unknown key to a field to suggest it.
+const SIMILARITY: f64 = 0.8;
+
+/// Exit code when the run was aborted because of invalid config values, e.g.
+/// missing source folder or list file.
+pub const EXIT_ABORTED: i32 = 1;
+/// Exit code when at least one repository failed to clone or fetch. It's not 2,
+/// which clap exits with on invalid command line.
+pub const EXIT_REPOS_FAILED: i32 = 6;
+/// Exit code when json report can't be written, while all repositories succeeded.
+pub const EXIT_REPORT: i32 = 7;
+/// Exit code when config file is not provided or can't be read or written.
+pub const EXIT_CONFIG_READ: i32 = 3;
+/// Exit code when config file is not valid json, toml or yaml, has unknown keys
+/// or values of wrong type.
+pub const EXIT_CONFIG_INVALID: i32 = 4;
+/// Exit code when secret reference of config can't be resolved.
+pub const EXIT_SECRET: i32 = 5;
+
+#[derive(Debug)]
+pub enum FreshgitError {
+    /// Config is not passed with `-c/--config` and none is found in standard
+    /// locations.
+    ConfigMissing,
+    /// Config file can't be opened or read.
+    ConfigRead { path: PathBuf, source: io::Error },
+    /// Config file can't be written, e.g. by conversion to another format.
+    ConfigWrite { path: PathBuf, message: String },
+    /// Config file is not valid in its format, or its value doesn't match the field.
+    ConfigParse {
+        path: PathBuf,
+        format: &'static str,
+        /// Line and column, both starting from 1.
+        position: Option<(usize, usize)>,
+        syntax: bool,
+        message: String,
+    },
+    /// Config field has value which can't be used, e.g. empty source folder.
+    ConfigValue {
+        /// Layer the value was taken from, `None` if it's not set.
+        origin: Option<String>,
+        field: String,
+        message: String,
+    },
+    /// Secret field of config has reference which can't be resolved.
+    Secret { field: String, message: String },
+    /// Config lock was poisoned by a panicked thread.
+    ConfigLock,
+    /// Run was aborted because of config values, the reason is already logged.
+    Aborted,
+}
+
+impl FreshgitError {
+    /// Creates parse error of config at `path` in `format`, position is printed
+    /// by parsers at the end of `message` and is moved out of it. Text of the
+    /// line is never kept, it can contain secrets.
+    pub fn parse(
+        path: &Path,
+        format: &'static str,
+        message: String,
+        position: Option<(usize, usize)>,
+        syntax: bool,
+    ) -> FreshgitError {
+        let message = match position {
+            Some((line, column)) => message
+                .strip_suffix(&format!(" at line {} column {}", line, column))
+                .unwrap_or(&message)
+                .to_string(),
+            None => message,
+        };
+        let message = suggest_field(&message).unwrap_or(message);
+
+        FreshgitError::ConfigParse {
+            path: path.to_path_buf(),
+            format,
+            position,
+            syntax,
+            message,
+        }
+    }
+
+    /// Creates error of config value set by `origin`, e.g. environment variable,
+    /// misspelled field names get the closest field suggested.
+    pub fn value(origin: &str, field: &str, message: String) -> FreshgitError {
+        FreshgitError::ConfigValue {
+            origin: Some(origin.to_string()),
+            field: field.to_string(),
+            message: suggest_field(&message).unwrap_or(message),
+        }
+    }
+
+    /// Exit code freshgit finishes with because of this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FreshgitError::ConfigMissing
+            | FreshgitError::ConfigRead { .. }
+            | FreshgitError::ConfigWrite { .. } => EXIT_CONFIG_READ,
+            FreshgitError::ConfigParse { .. } | FreshgitError::ConfigValue { .. } => {
+                EXIT_CONFIG_INVALID
+            }
+            FreshgitError::Secret { .. } => EXIT_SECRET,
+            FreshgitError::ConfigLock | FreshgitError::Aborted => EXIT_ABORTED,
+        }
+    }
+}
+
+impl Display for FreshgitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreshgitError::ConfigMissing => write!(
+                f,
+                "Config file is not found, it has to be provided with -c/--config or {}, \
+                 or placed to ./freshgit.toml, $XDG_CONFIG_HOME/freshgit or /etc/freshgit",
+                crate::config_file::ENV_CONFIG
+            ),
+            FreshgitError::ConfigRead { path, source } => {
+                write!(
+                    f,
+                    "Could not read config file {}: {}",
+                    path.display(),
+                    source
+                )
+            }
+            FreshgitError::ConfigWrite { path, message } => {
+                write!(
+                    f,
+                    "Could not write config file {}: {}",
+                    path.display(),
+                    message
+                )
+            }
+            FreshgitError::ConfigParse {
+                path,
+                format,
+                position,
+                syntax,
+                message,
+            } => {
+                match syntax {
+                    true => write!(f, "Config file {} is not valid {}", path.display(), format)?,
+                    false => write!(f, "Config file {} is not valid config", path.display())?,
+                }
+                if let Some((line, column)) = position {
+                    write!(f, " at line {}, column {}", line, column)?;
+                }
+                write!(f, ": {}", message)
+            }
+            FreshgitError::ConfigValue {
+                origin,
+                field,
+                message,
+            } => match origin {
+                Some(o) => write!(f, "Config value {} from {}: {}", field, o, message),
+                None => write!(f, "Config value {}: {}", field, message),
+            },
+            FreshgitError::Secret { field, message } => {
+                write!(f, "Could not resolve {}: {}", field, message)
+            }
+            FreshgitError::ConfigLock => write!(f, "Config is not available, lock is poisoned"),
+            FreshgitError::Aborted => write!(f, "Run was aborted because of config problems"),
+        }
+    }
+}
+
+impl Error for FreshgitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FreshgitError::ConfigRead { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces serde message listing all fields for unknown key, e.g.
+/// "unknown field `src_foldr`, expected one of `config_path`, ...", with the
+/// closest field. `None` if message is not about unknown key or nothing is close.
+fn suggest_field(message: &str) -> Option<String> {
+    let (head, rest) = message.split_at(message.find(UNKNOWN_FIELD)?);
+    let (key, expected) = rest[UNKNOWN_FIELD.len()..].split_once('`')?;
+    // Toml names the table after the list of fields
+    let (expected, tail) = match expected.split_once(FOR_KEY) {
+        Some((e, t)) => (e, format!("{}{}", FOR_KEY, t)),
+        None => (expected, String::new()),
+    };
+    let closest = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|f| (strsim::jaro_winkler(key, f), f))
+        .filter(|(sim, _)| *sim >= SIMILARITY)
+        .max_by(|a, b| a.0.total_cmp(&b.0))?;
+
+    Some(format!(
+        "{}{}{}`, did you mean `{}`?{}",
+        head, UNKNOWN_FIELD, key, closest.1, tail
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggests_closest_field() {
+        assert_eq!(
+            suggest_field(
+                "unknown field `src_foldr`, expected one of `src_folder`, `files_to_read`"
+            )
+            .unwrap(),
+            "unknown field `src_foldr`, did you mean `src_folder`?"
+        );
+        // Toml tells the table of the key after the fields
+        assert_eq!(
+            suggest_field(
+                "unknown field `max_job`, expected one of `max_jobs`, `delay_ms` for key `hosts.github.com`"
+            )
+            .unwrap(),
+            "unknown field `max_job`, did you mean `max_jobs`? for key `hosts.github.com`"
+        );
+        assert_eq!(
+            suggest_field("unknown field `zzz`, expected one of `src_folder`, `max_jobs`"),
+            None
+        );
+        assert_eq!(suggest_field("invalid type: string, expected usize"), None);
+    }
+
+    #[test]
+    fn moves_position_out_of_message() {
+        let e = FreshgitError::parse(
+            Path::new("c.json"),
+            "json",
+            "expected `,` or `}` at line 3 column 7".to_string(),
+            Some((3, 7)),
+            true,
+        );
+        assert_eq!(
+            e.to_string(),
+            "Config file c.json is not valid json at line 3, column 7: expected `,` or `}`"
+        );
+        assert_eq!(e.exit_code(), EXIT_CONFIG_INVALID);
+    }
+}
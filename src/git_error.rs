@@ -0,0 +1,237 @@
+//! Classifies failures of git processes by their output, so transient problems
+//! can be retried and permanent ones are reported right away.
+use core::fmt;
+use serde::Serialize;
+use std::fmt::Display;
+
+/// Beginnings of prompts git or ssh would wait on forever, since nobody is there
+/// to answer.
+const PROMPTS: [&str; 5] = [
+    "enter passphrase",
+    "username for '",
+    "password for '",
+    "are you sure you want to continue connecting",
+    "verification code:",
+];
+/// Ending of ssh password prompt, which starts with `user@host`.
+const SSH_PASSWORD_PROMPT: &str = "'s password:";
+
+/// Messages telling there is no space left to write the repository.
+const DISK_FULL: [&str; 3] = [
+    "no space left on device",
+    "disk quota exceeded",
+    "not enough space",
+];
+
+/// Messages telling local repository is damaged, fetch won't fix it.
+const CORRUPT_REPO: [&str; 9] = [
+    "object file is empty",
+    "is corrupt",
+    "corrupt loose object",
+    "index file corrupt",
+    "bad object",
+    "missing blob",
+    "missing tree",
+    "broken link from",
+    "not a git repository",
+];
+
+/// Messages of git (and ssh, curl) telling repository doesn't exist.
+const NOT_FOUND: [&str; 6] = [
+    "repository not found",
+    "' not found",
+    "does not appear to be a git repository",
+    "does not exist",
+    "returned error: 404",
+    "project not found",
+];
+
+/// Messages telling credentials are missing or not accepted.
+const AUTH_FAILED: [&str; 10] = [
+    "authentication failed",
+    "permission denied",
+    "could not read username",
+    "could not read password",
+    "terminal prompts disabled",
+    "invalid username or password",
+    "returned error: 401",
+    "returned error: 403",
+    "host key verification failed",
+    "access denied",
+];
+
+/// Messages telling connection to the remote was not established or was broken.
+const NETWORK: [&str; 17] = [
+    "could not resolve host",
+    "temporary failure in name resolution",
+    "connection reset",
+    "connection refused",
+    "connection timed out",
+    "operation timed out",
+    "network is unreachable",
+    "failed to connect",
+    "early eof",
+    "rpc failed",
+    "the remote end hung up unexpectedly",
+    "unexpected disconnect",
+    "returned error: 5",
+    "gnutls",
+    "ssl_read",
+    "ssl_connect",
+    "broken pipe",
+];
+
+/// Category of git failure.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitError {
+    AuthFailed,
+    NotFound,
+    NetworkError,
+    /// Git or ssh asked for passphrase, password or confirmation and was stopped.
+    PassphrasePrompt,
+    DiskFull,
+    CorruptRepo,
+    Unknown,
+}
+
+impl GitError {
+    /// Classifies failure by the output of git, permanent problems are checked
+    /// first, since git often reports them followed by generic connection errors.
+    pub fn classify<S: AsRef<str>>(output: &[S]) -> GitError {
+        let lines: Vec<String> = output.iter().map(|l| l.as_ref().to_lowercase()).collect();
+        let has = |msgs: &[&str]| lines.iter().any(|l| msgs.iter().any(|m| l.contains(m)));
+
+        if output.iter().any(|l| Self::is_prompt(l.as_ref())) {
+            GitError::PassphrasePrompt
+        } else if has(&DISK_FULL) {
+            GitError::DiskFull
+        } else if has(&CORRUPT_REPO) {
+            GitError::CorruptRepo
+        } else if has(&NOT_FOUND) {
+            GitError::NotFound
+        } else if has(&AUTH_FAILED) {
+            GitError::AuthFailed
+        } else if has(&NETWORK) {
+            GitError::NetworkError
+        } else {
+            GitError::Unknown
+        }
+    }
+
+    /// Checks whether the line is a prompt git would block on. Only prompts are
+    /// checked while git is running, other problems are left for git to report.
+    pub fn is_prompt(line: &str) -> bool {
+        let line = line.trim().to_lowercase();
+        PROMPTS.iter().any(|p| line.starts_with(p)) || line.ends_with(SSH_PASSWORD_PROMPT)
+    }
+
+    /// Transient failures are worth retrying.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, GitError::NetworkError)
+    }
+}
+
+impl Display for GitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitError::AuthFailed => write!(f, "authentication failed"),
+            GitError::NotFound => write!(f, "repository not found"),
+            GitError::NetworkError => write!(f, "network error"),
+            GitError::PassphrasePrompt => write!(f, "waiting for passphrase"),
+            GitError::DiskFull => write!(f, "disk full"),
+            GitError::CorruptRepo => write!(f, "corrupt repository"),
+            GitError::Unknown => write!(f, "unknown error"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_git_output() {
+        for (output, expected) in [
+            (
+                &[
+                    "ERROR: Repository not found.",
+                    "fatal: Could not read from remote repository.",
+                ][..],
+                GitError::NotFound,
+            ),
+            (
+                &[
+                    "remote: Invalid username or password.",
+                    "fatal: Authentication failed for 'https://host/o/r.git/'",
+                ],
+                GitError::AuthFailed,
+            ),
+            (
+                &[
+                    "git@host: Permission denied (publickey).",
+                    "fatal: Could not read from remote repository.",
+                ],
+                GitError::AuthFailed,
+            ),
+            (
+                &["ssh: Could not resolve hostname host: Temporary failure in name resolution"],
+                GitError::NetworkError,
+            ),
+            (
+                &[
+                    "error: RPC failed; curl 56 GnuTLS recv error (-9)",
+                    "fatal: early EOF",
+                ],
+                GitError::NetworkError,
+            ),
+            (
+                &[
+                    "fatal: write error: No space left on device",
+                    "fatal: the remote end hung up unexpectedly",
+                ],
+                GitError::DiskFull,
+            ),
+            (
+                &[
+                    "error: object file .git/objects/ab/cd is empty",
+                    "fatal: loose object abcd is corrupt",
+                ],
+                GitError::CorruptRepo,
+            ),
+            (&["fatal: something else"], GitError::Unknown),
+            (&[], GitError::Unknown),
+        ] {
+            assert_eq!(GitError::classify(output), expected, "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn prompts_win_over_other_errors() {
+        let output = [
+            "fatal: the remote end hung up unexpectedly",
+            "Enter passphrase for key '/home/user/.ssh/id_ed25519':",
+        ];
+        assert_eq!(GitError::classify(&output), GitError::PassphrasePrompt);
+    }
+
+    #[test]
+    fn detects_prompts() {
+        assert!(GitError::is_prompt("Username for 'https://github.com': "));
+        assert!(GitError::is_prompt("git@host's password:"));
+        assert!(GitError::is_prompt(
+            "Are you sure you want to continue connecting (yes/no/[fingerprint])?"
+        ));
+        assert!(!GitError::is_prompt("remote: Counting objects: 100% (5/5)"));
+        assert!(!GitError::is_prompt(
+            "fatal: could not read Password for 'https://host'"
+        ));
+    }
+
+    #[test]
+    fn only_network_errors_are_transient() {
+        assert!(GitError::NetworkError.is_transient());
+        assert!(!GitError::AuthFailed.is_transient());
+        assert!(!GitError::NotFound.is_transient());
+    }
+}
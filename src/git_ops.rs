@@ -3,23 +3,30 @@
 //! corresponding process. While process is running, it's stdout and stderr are
 //! captured to check if transaction is possible and if it's not there is an
 //! attempt to kill process to free the runtime slot for new process.
-use crate::dl_upd::Config;
-use csv::ReaderBuilder;
+use crate::askpass;
+use crate::credentials::{Credential, Credentials};
+use crate::dl_upd::{Config, SshKey};
+use crate::git_error::GitError;
+use crate::limits::{Limiter, Retries, TimeLimits, Timeouts};
+use crate::lists;
+use crate::remote::RemoteAddr;
+use crate::report::{Outcome, RepoResult, Summary};
+use crate::secret::{self, Secret};
+use crate::ssh::{self, SshKeys};
 use futures::future::join_all;
 use lazy_static::lazy_static;
-use log::{error, info, warn};
-use std::collections::HashSet;
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::BufRead;
-use std::path::PathBuf;
-use std::process::Stdio;
-use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::{exit, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
 use tokio::{self, runtime};
-use url::Url;
 use walkdir::WalkDir;
 
 const SRC_EXISTS: &str = "Source folder exists, continuing";
@@ -30,74 +37,217 @@ const GIT_NUSERNAME: &str = "Git username is not provided, login may fail";
 const GIT_NPASSWORD: &str = "Git password is not provided, login may fail";
 const SSH_NASKPASS: &str = "SSH askpass is not provided, login may fail";
 const WALKDIR_ERR: &str = "Could not walk directory";
-const ENV_GIT_USERNAME: &str = "GIT_USERNAME";
-const ENV_GIT_PASSWORD: &str = "GIT_PASSWORD";
-const ENV_SSH_ASKPASS: &str = "SSH_ASKPASS";
+const LISTS_ERR: &str = "At least one repository list could not be read, aborting";
+const DEFAULT_MAX_JOBS: usize = 8;
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_DELAY_MS: u64 = 1000;
+/// Amount of last lines of git stderr kept to classify and report failure.
+const STDERR_TAIL: usize = 20;
+/// Unfinished line is taken for a prompt if nothing follows it for this long.
+const PROMPT_WAIT: Duration = Duration::from_millis(500);
+/// Output left in the pipes is read for this long after git exits.
+const OUTPUT_DRAIN: Duration = Duration::from_secs(1);
+const ENV_GIT_TERMINAL_PROMPT: &str = "GIT_TERMINAL_PROMPT";
+const ENV_GIT_SSH_COMMAND: &str = "GIT_SSH_COMMAND";
 const ENV_GIT_ASKPASS: &str = "GIT_ASKPASS";
+const ENV_SSH_ASKPASS: &str = "SSH_ASKPASS";
+const ENV_SSH_ASKPASS_REQUIRE: &str = "SSH_ASKPASS_REQUIRE";
+const ENV_GCM_INTERACTIVE: &str = "GCM_INTERACTIVE";
+const ENV_GIT_CONFIG_COUNT: &str = "GIT_CONFIG_COUNT";
 
 lazy_static! {
-    static ref GIT_OUT: HashSet<&'static str> = HashSet::from_iter([
-        "Failed",
-        "failed",
-        "Enter passphrase",
-        "enter passphrase",
-        "Couldn't",
-        "Could not",
-        "couldn't",
-        "could not",
-        "Error",
-        "error",
-        "Traceback",
-        "404",
-        "Enter passphrase for key",
-        "fatal",
-        "denied",
-    ]);
-}
-
-#[derive(Copy, Clone, PartialEq, Eq)]
+    /// Process groups of running git processes and credential helpers, with
+    /// clone folders they create, killed and removed if freshgit is interrupted.
+    static ref RUNNING: Mutex<HashMap<u32, Option<PathBuf>>> = Mutex::new(HashMap::new());
+}
+
+/// Settings shared by all git processes of the run.
+struct RunConfig {
+    git_username: String,
+    git_password: Secret,
+    ssh_askpass: Secret,
+    /// Path of freshgit itself, used as askpass program when there are
+    /// credentials to answer prompts with.
+    askpass: Option<PathBuf>,
+    credentials: Credentials,
+    ssh_keys: SshKeys,
+    timeouts: Timeouts,
+    retries: Retries,
+}
+
+impl RunConfig {
+    /// Finds credentials, ssh key and token of the remote, and ssh command of the
+    /// repository in `cd`, `None` for clone.
+    async fn auth(&self, ra: Option<&RemoteAddr>, cd: Option<&Path>) -> RepoAuth<'_> {
+        let ssh_base = ssh::base_command(cd).await;
+        let git_helpers = self.credentials.uses_git_helpers(ra);
+        match ra {
+            Some(ra) => RepoAuth {
+                cred: self.credentials.get(ra).await,
+                ssh_key: self.ssh_keys.for_remote(ra),
+                header: self.credentials.token_header(ra),
+                ssh_base,
+                git_helpers,
+            },
+            None => RepoAuth {
+                ssh_base,
+                git_helpers,
+                ..RepoAuth::default()
+            },
+        }
+    }
+}
+
+/// Authentication of a single repository, passed to git when it's spawned.
+#[derive(Default)]
+struct RepoAuth<'a> {
+    /// Credentials from credential helper, config ones are used if not found.
+    cred: Option<Arc<Credential>>,
+    ssh_key: Option<&'a SshKey>,
+    /// Git config key and value of http header with token, https only.
+    header: Option<(String, Secret)>,
+    /// Ssh command git would run without freshgit, options are added to it.
+    ssh_base: String,
+    /// Credential helpers configured in git are kept for git process.
+    git_helpers: bool,
+}
+
+/// Result of a single supervised git process.
+struct GitRun {
+    finished: Finished,
+    /// Last lines of git stderr.
+    stderr: Vec<String>,
+}
+
+impl GitRun {
+    /// Category of failure, `None` if git succeeded or was killed by time limits.
+    fn error(&self) -> Option<GitError> {
+        match &self.finished {
+            Finished::Exited(status) if status.success() => None,
+            Finished::Exited(_) | Finished::Prompted => Some(GitError::classify(&self.stderr)),
+            Finished::TimedOut(_) | Finished::Stalled(_) => None,
+        }
+    }
+}
+
+/// Output stream of git process.
+#[derive(Copy, Clone, Debug)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// How supervised git process has finished.
+enum Finished {
+    Exited(ExitStatus),
+    /// Killed after running longer than allowed.
+    TimedOut(Duration),
+    /// Killed after not printing anything for too long.
+    Stalled(Duration),
+    /// Killed after asking for input nobody could give.
+    Prompted,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum GitMode {
     FETCH,
     CLONE,
 }
 
 /// Checks passed configuration and also sets sane defaults before running clone
-/// or getch processes.
-pub fn git_config_and_run(conf: Config, mode: GitMode) {
-    let src_folder = conf.src_folder.unwrap_or(PathBuf::new());
-    let files_to_read = conf.files_to_read.unwrap_or(Vec::<PathBuf>::new());
-    let git_username = conf.git_username.unwrap_or("git".to_string());
-    let git_password = conf.git_password.unwrap_or("pass".to_string());
-    let ssh_askpass = conf.ssh_askpass.unwrap_or("pass".to_string());
+/// or getch processes. Returns outcome of every repository, or `None` if the run
+/// was aborted because of configuration problems.
+pub fn git_config_and_run(conf: Config, mode: GitMode) -> Option<Summary> {
+    let src_folder = conf.src_folder.unwrap_or_default();
+    let files_to_read = conf.files_to_read.unwrap_or_default();
+    let git_username = conf.git_username.unwrap_or_default();
+    let git_password = conf.git_password.unwrap_or_default();
+    let ssh_askpass = conf.ssh_askpass.unwrap_or_default();
     let async_exec = conf.async_exec.unwrap_or(false);
+    let csv_column = conf.csv_column.unwrap_or("repository".to_string());
+    let max_jobs = conf.max_jobs.unwrap_or(DEFAULT_MAX_JOBS);
+    let hosts = conf.hosts.unwrap_or_default();
+    let timeouts = Timeouts::new(
+        conf.timeout_secs,
+        conf.stall_timeout_secs,
+        &conf.repo_timeouts.unwrap_or_default(),
+    );
+    let retries = Retries::new(
+        conf.retries.unwrap_or(DEFAULT_RETRIES),
+        conf.retry_delay_ms.unwrap_or(DEFAULT_RETRY_DELAY_MS),
+    );
 
     if src_folder.exists() {
         info!("{}", SRC_EXISTS);
     } else {
         error!("{}: {:#?}", SRC_NEXISTS, src_folder);
-        return;
+        return None;
     }
 
-    if git_username.len() == 0 {
+    if git_username.is_empty() {
         info!("{}", GIT_NUSERNAME)
     }
-    if git_password.len() == 0 {
+    if git_password.is_empty() {
         info!("{}", GIT_NPASSWORD)
     }
-    if ssh_askpass.len() == 0 {
+    if ssh_askpass.is_empty() {
         info!("{}", SSH_NASKPASS)
     }
 
-    let git_username = Arc::new(git_username);
-    let git_password = Arc::new(git_password);
-    let ssh_askpass = Arc::new(ssh_askpass);
+    let askpass = match std::env::current_exe() {
+        Ok(exe) => Some(exe),
+        Err(e) => {
+            warn!(
+                "Credentials can't be passed to git, path of freshgit is unknown: {}",
+                e
+            );
+            None
+        }
+    };
+    let credentials = Credentials::new(conf.credential_helper, &hosts);
+    let ssh_keys = SshKeys::new(&hosts);
+
+    let run_config = Arc::new(RunConfig {
+        git_username,
+        git_password,
+        ssh_askpass,
+        askpass,
+        credentials,
+        ssh_keys,
+        timeouts,
+        retries,
+    });
+
+    if max_jobs == 0 {
+        error!("Max jobs has to be at least 1, aborting");
+        return None;
+    }
+    for (h, hc) in hosts.iter() {
+        if hc.max_jobs == Some(0) {
+            error!("Max jobs of host {} has to be at least 1, aborting", h);
+            return None;
+        }
+        info!(
+            "Host {} limits: {:?} at once, {} ms between requests",
+            h,
+            hc.max_jobs,
+            hc.delay_ms.unwrap_or(0)
+        );
+    }
+    let limiter = Arc::new(Limiter::new(max_jobs, &hosts));
 
     if !async_exec {
         info!("Updates will run in synchronous mode");
     } else {
-        info!("Updates will run in asynchronous mode");
+        info!(
+            "Updates will run in asynchronous mode, up to {} at once",
+            max_jobs
+        );
     }
 
+    watch_signals();
     match mode {
         GitMode::CLONE => {
             let mut files_ne = Vec::<PathBuf>::new();
@@ -108,25 +258,23 @@ pub fn git_config_and_run(conf: Config, mode: GitMode) {
                 }
             }
 
-            if files_ne.len() > 0 {
-                return;
+            if !files_ne.is_empty() {
+                return None;
             } else {
                 info!("{}", FILES_EXIST);
             }
 
-            let repos = read_repo_lists(&src_folder, files_to_read);
+            let repos = match read_repo_lists(&src_folder, files_to_read, &csv_column) {
+                Some(rp) => rp,
+                None => {
+                    error!("{}", LISTS_ERR);
+                    return None;
+                }
+            };
 
-            clone_repos(git_username, git_password, ssh_askpass, async_exec, repos);
-        }
-        GitMode::FETCH => {
-            walk_fetch(
-                src_folder,
-                git_username,
-                git_password,
-                ssh_askpass,
-                async_exec,
-            );
+            Some(clone_repos(async_exec, limiter, run_config, repos))
         }
+        GitMode::FETCH => Some(walk_fetch(src_folder, async_exec, limiter, run_config)),
     }
 }
 
@@ -159,332 +307,1027 @@ fn create_tokio_runtime(ae: bool) -> runtime::Runtime {
 }
 
 /// Walks all the folders in provided root folder and tries to check out changes if git repository
-/// is detected.
-fn walk_fetch(src_folder: PathBuf, gu: Arc<String>, gp: Arc<String>, sa: Arc<String>, ae: bool) {
+/// is detected. Fetches are started when limiter allows it for the host of `origin` remote.
+fn walk_fetch(src_folder: PathBuf, ae: bool, lm: Arc<Limiter>, rc: Arc<RunConfig>) -> Summary {
     let rt = create_tokio_runtime(ae);
-    let mut jhs: Vec<JoinHandle<_>> = vec![];
+    let mut summary = Summary::default();
+    let mut jhs: Vec<(String, PathBuf, JoinHandle<RepoResult>)> = vec![];
 
     for f in WalkDir::new(src_folder).into_iter() {
         match f {
             Ok(fl) => {
-                if fl.path().is_dir() {
-                    if fl.file_name() == ".git" {
-                        let gu = gu.clone();
-                        let gp = gp.clone();
-                        let sa = sa.clone();
-
-                        if !ae {
-                            rt.block_on(async {
-                                git_fetch(fl.into_path(), gu, gp, sa).await;
-                            })
-                        } else {
-                            let jh = rt.spawn(async {
-                                tokio::task::spawn(git_fetch(fl.into_path(), gu, gp, sa));
-                            });
-                            jhs.push(jh);
+                if fl.path().is_dir() && fl.file_name() == ".git" {
+                    let lm = lm.clone();
+                    let rc = rc.clone();
+                    let cd = fl.into_path();
+
+                    let fetch = {
+                        let cd = cd.clone();
+                        async move {
+                            let origin = {
+                                let _permit = lm.acquire(None).await;
+                                origin_remote(&cd).await
+                            };
+                            let _permit = lm.acquire(origin.as_ref().and_then(|o| o.host())).await;
+                            git_fetch(cd, origin, rc).await
                         }
                     };
+
+                    if !ae {
+                        summary.results.push(rt.block_on(fetch));
+                    } else {
+                        let repo = cd.to_string_lossy().to_string();
+                        jhs.push((repo, cd, rt.spawn(fetch)));
+                    }
                 }
             }
             Err(e) => error!("{}: {}", WALKDIR_ERR, e),
         }
     }
 
-    if jhs.len() > 0 {
-        rt.block_on(async {
-            join_all(jhs).await;
-        })
+    if !jhs.is_empty() {
+        summary
+            .results
+            .extend(rt.block_on(join_results(jhs, GitMode::FETCH)));
     }
+
+    summary
 }
 
-/// Clones provided repositories using sync or async tokio runtimes.
+/// Clones provided repositories using sync or async tokio runtimes. Clones are
+/// started when limiter allows it for the host of repository. Repositories
+/// colliding with an earlier one in the same folder are reported as failed.
 fn clone_repos(
-    gu: Arc<String>,
-    gp: Arc<String>,
-    sa: Arc<String>,
     ae: bool,
-    rp: Vec<(Url, PathBuf)>,
-) {
+    lm: Arc<Limiter>,
+    rc: Arc<RunConfig>,
+    rp: Vec<(RemoteAddr, PathBuf)>,
+) -> Summary {
     let rt = create_tokio_runtime(ae);
-    let mut jhs: Vec<JoinHandle<_>> = vec![];
+    let mut summary = Summary::default();
+    let mut jhs: Vec<(String, PathBuf, JoinHandle<RepoResult>)> = vec![];
+    let mut targets = HashMap::<PathBuf, RemoteAddr>::new();
 
     for repo in rp {
-        let gu = gu.clone();
-        let gp = gp.clone();
-        let sa = sa.clone();
+        // Clones into the same folder would break each other, so only the first
+        // address of the folder is cloned
+        if let Some(first) = targets.get(&repo.1) {
+            if first.as_str() == repo.0.as_str() {
+                info!("Skipping duplicate of repository: {}", repo.0);
+                continue;
+            }
+            let reason = format!("collides with {} in {}", first, repo.1.display());
+            warn!("Repository {} {}", repo.0, reason);
+            summary.results.push(RepoResult::new(
+                repo.0.to_string(),
+                repo.1,
+                GitMode::CLONE,
+                Outcome::Failed(reason),
+            ));
+            continue;
+        }
+        targets.insert(repo.1.clone(), repo.0.clone());
+
+        if repo.1.is_dir() {
+            info!(
+                "Repository is already cloned, use update instead: {}",
+                repo.1.to_string_lossy()
+            );
+            summary.results.push(RepoResult::new(
+                repo.0.to_string(),
+                repo.1,
+                GitMode::CLONE,
+                Outcome::AlreadyExists,
+            ));
+            continue;
+        }
+
+        let lm = lm.clone();
+        let rc = rc.clone();
+        let (addr, cd) = (repo.0.to_string(), repo.1.clone());
+
+        let clone = async move {
+            let _permit = lm.acquire(repo.0.host()).await;
+            git_clone(repo, rc).await
+        };
 
         if !ae {
-            rt.block_on(async {
-                git_clone(repo, gu, gp, sa).await;
-            })
+            summary.results.push(rt.block_on(clone));
         } else {
-            let jh = rt.spawn(async {
-                tokio::task::spawn(git_clone(repo, gu, gp, sa));
-            });
-            jhs.push(jh);
+            jhs.push((addr, cd, rt.spawn(clone)));
         }
     }
 
-    if jhs.len() > 0 {
-        rt.block_on(async {
-            join_all(jhs).await;
-        })
+    if !jhs.is_empty() {
+        summary
+            .results
+            .extend(rt.block_on(join_results(jhs, GitMode::CLONE)));
     }
+
+    summary
+}
+
+/// Waits for all spawned clones or fetches, task which panicked is reported as failed.
+async fn join_results(
+    jhs: Vec<(String, PathBuf, JoinHandle<RepoResult>)>,
+    mode: GitMode,
+) -> Vec<RepoResult> {
+    let (repos, handles): (Vec<_>, Vec<_>) = jhs.into_iter().map(|(r, p, h)| ((r, p), h)).unzip();
+
+    join_all(handles)
+        .await
+        .into_iter()
+        .zip(repos)
+        .map(|(res, (repo, path))| match res {
+            Ok(rr) => rr,
+            Err(e) => RepoResult::new(repo, path, mode, Outcome::Failed(e.to_string())),
+        })
+        .collect()
 }
 
 /// Clones provided repository using tokio::process::Command.
-async fn git_clone(rp: (Url, PathBuf), gu: Arc<String>, gp: Arc<String>, sa: Arc<String>) {
-    if rp.1.exists() && rp.1.is_dir() {
-        info!(
-            "Repository is already cloned, use update instead: {}",
-            rp.1.to_str().unwrap_or("error unwrapping")
-        );
-        return;
-    }
-
-    info!("Cloning: {} {}", &rp.0.to_string(), &rp.1.to_str().unwrap());
-    let cmd: tokio::process::Child = Command::new("git")
-        .env(ENV_GIT_USERNAME, gu.as_str())
-        .env(ENV_GIT_PASSWORD, gp.as_str())
-        .env(ENV_SSH_ASKPASS, sa.as_str())
-        .env(ENV_GIT_ASKPASS, sa.as_str())
-        .arg("clone")
-        .arg("--recursive")
-        .arg(format!("{}", rp.0))
-        .arg(rp.1)
-        .stdout(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to execute child");
+async fn git_clone(rp: (RemoteAddr, PathBuf), rc: Arc<RunConfig>) -> RepoResult {
+    let mut res = RepoResult::new(rp.0.to_string(), rp.1, GitMode::CLONE, Outcome::Cloned);
+    let repo = res.repo.clone();
+    let tl = rc.timeouts.for_repo(&repo, &res.path);
+    let started = Instant::now();
 
-    let _res = control_process(cmd, rp.0.as_str(), GitMode::CLONE).await;
+    info!("Cloning: {} {}", &repo, &res.path.to_string_lossy());
+    let auth = rc.auth(Some(&rp.0), None).await;
+    let git_cmd = || {
+        let mut cmd = git_command(&rc, &auth);
+        cmd.arg("clone")
+            .arg("--recursive")
+            .args(progress_arg(&tl))
+            .arg(rp.0.as_str())
+            .arg(&res.path);
+        cmd
+    };
+
+    let (run, attempts) = run_git(
+        git_cmd,
+        &repo,
+        GitMode::CLONE,
+        tl,
+        &rc.retries,
+        Some(&res.path),
+    )
+    .await;
+    res.duration = started.elapsed();
+    res.attempts = attempts;
+    match run {
+        Ok(GitRun {
+            finished: Finished::Exited(status),
+            ..
+        }) if status.success() => {
+            res.exit_code = status.code();
+            res.new_head = head_commit(&res.path, GitMode::CLONE).await;
+        }
+        run => failure(&mut res, run),
+    }
+
+    res
 }
 
 /// Fetches detected repository using tokio::process::Command.
-async fn git_fetch(cd: PathBuf, gu: Arc<String>, gp: Arc<String>, sa: Arc<String>) {
-    if cd.exists() && cd.is_dir() {
-        info!("Updating: {}", cd.to_string_lossy());
-    } else {
-        return;
+async fn git_fetch(cd: PathBuf, origin: Option<RemoteAddr>, rc: Arc<RunConfig>) -> RepoResult {
+    // Move out the .git folder
+    let mut cd = cd;
+    cd.pop();
+    let auth = rc.auth(origin.as_ref(), Some(&cd)).await;
+    let repo = match origin {
+        Some(o) => o.to_string(),
+        None => cd.to_string_lossy().to_string(),
+    };
+    let mut res = RepoResult::new(repo.clone(), cd.clone(), GitMode::FETCH, Outcome::Fetched);
+    let tl = rc.timeouts.for_repo(&repo, &cd);
+    let started = Instant::now();
+
+    info!("Updating: {}", cd.to_string_lossy());
+    let refs_before = refs_snapshot(&cd).await;
+    res.old_head = head_commit(&cd, GitMode::FETCH).await;
+
+    let git_cmd = || {
+        let mut cmd = git_command(&rc, &auth);
+        cmd.current_dir(&cd)
+            .arg("fetch")
+            .arg("--all")
+            .arg("--tags")
+            .arg("--auto-gc")
+            .args(progress_arg(&tl));
+        cmd
+    };
+
+    let (run, attempts) = run_git(git_cmd, &repo, GitMode::FETCH, tl, &rc.retries, None).await;
+    res.duration = started.elapsed();
+    res.attempts = attempts;
+    match run {
+        Ok(GitRun {
+            finished: Finished::Exited(status),
+            ..
+        }) if status.success() => {
+            res.exit_code = status.code();
+            res.new_head = head_commit(&cd, GitMode::FETCH).await;
+            if refs_before.is_some() && refs_snapshot(&cd).await == refs_before {
+                res.outcome = Outcome::UpToDate;
+            }
+        }
+        run => failure(&mut res, run),
     }
 
-    // Move out the .git folder
-    cd.clone().pop();
-
-    let cmd: tokio::process::Child = Command::new("git")
-        .current_dir(&cd)
-        .env(ENV_GIT_USERNAME, gu.as_str())
-        .env(ENV_GIT_PASSWORD, gp.as_str())
-        .env(ENV_SSH_ASKPASS, sa.as_str())
-        .env(ENV_GIT_ASKPASS, sa.as_str())
-        .arg("fetch")
-        .arg("--all")
-        .arg("--tags")
-        .arg("--auto-gc")
+    res
+}
+
+/// Runs git command created by `git_cmd` until it succeeds or fails permanently,
+/// transient failures are retried with exponential backoff. Folder `clean` is
+/// removed when freshgit kills git, since killed git leaves partially cloned
+/// repository behind, which would be taken for already cloned one. It's only
+/// removed if the attempt created it, git removes it by itself on other failures.
+/// Returns result of the last attempt and amount of attempts.
+async fn run_git<F: Fn() -> Command>(
+    git_cmd: F,
+    repo: &str,
+    mode: GitMode,
+    tl: TimeLimits,
+    rt: &Retries,
+    clean: Option<&Path>,
+) -> (
+    Result<GitRun, Box<dyn std::error::Error + Send + Sync>>,
+    u32,
+) {
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let existed = clean.is_some_and(|cl| cl.exists());
+        let cmd = match git_cmd().spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                error!("Failed to execute git: {}", e);
+                return (Err(format!("could not run git: {}", e).into()), attempt);
+            }
+        };
+
+        let running = Running::new(cmd.id().unwrap_or_default(), clean.filter(|_| !existed));
+        let run = control_process(cmd, repo, mode, tl).await;
+        let transient = match &run {
+            Ok(GitRun {
+                finished: Finished::Exited(status),
+                ..
+            }) if status.success() => return (run, attempt),
+            // Stalled connection is likely to recover, while retry of the process
+            // which was running for too long would just take the same time again
+            Ok(GitRun {
+                finished: Finished::Stalled(_),
+                ..
+            }) => true,
+            Ok(gr) => match gr.error() {
+                Some(ge) => {
+                    match mode {
+                        GitMode::FETCH => warn!("Problem fetching ({}): {}", ge, repo),
+                        GitMode::CLONE => warn!("Problem cloning ({}): {}", ge, repo),
+                    }
+                    ge.is_transient()
+                }
+                None => false,
+            },
+            Err(_) => false,
+        };
+
+        let killed = matches!(
+            &run,
+            Ok(GitRun {
+                finished: Finished::TimedOut(_) | Finished::Stalled(_) | Finished::Prompted,
+                ..
+            })
+        );
+        if let Some(cl) = clean.filter(|cl| killed && !existed && cl.exists()) {
+            if let Err(e) = tokio::fs::remove_dir_all(cl).await {
+                error!("Could not remove {}: {}", cl.to_string_lossy(), e);
+            }
+        }
+        drop(running);
+
+        if !transient || attempt > rt.retries {
+            return (run, attempt);
+        }
+
+        let delay = rt.backoff(attempt);
+        warn!(
+            "Retrying in {} ms, attempt {} of {}: {}",
+            delay.as_millis(),
+            attempt + 1,
+            rt.retries + 1,
+            repo
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Records unsuccessful result of `run_git`.
+fn failure(res: &mut RepoResult, run: Result<GitRun, Box<dyn std::error::Error + Send + Sync>>) {
+    let gr = match run {
+        Ok(gr) => gr,
+        Err(e) => {
+            res.outcome = Outcome::Failed(e.to_string());
+            return;
+        }
+    };
+
+    res.error_kind = gr.error();
+    res.stderr = gr.stderr.clone();
+    res.outcome = match (gr.finished, res.error_kind) {
+        (Finished::TimedOut(t), _) => {
+            Outcome::TimedOut(format!("git was running for more than {}s", t.as_secs()))
+        }
+        (Finished::Stalled(t), _) => {
+            Outcome::TimedOut(format!("git had no output for {}s", t.as_secs()))
+        }
+        (finished, ge) => {
+            let ge = ge.unwrap_or(GitError::Unknown);
+            if let Finished::Exited(status) = finished {
+                res.exit_code = status.code();
+            }
+            // Hints git prints after the error are less useful than the error itself
+            let reason = gr
+                .stderr
+                .iter()
+                .rev()
+                .find(|l| l.starts_with("fatal:") || l.starts_with("error:"))
+                .or(gr.stderr.last());
+            match reason {
+                Some(l) => Outcome::Failed(format!("{}: {}", ge, l)),
+                None => Outcome::Failed(ge.to_string()),
+            }
+        }
+    };
+}
+
+/// Creates git command which never waits for input: terminal prompts are off and
+/// prompts are answered by freshgit itself with credentials from credential
+/// helper or from config, or cancelled when there is nothing to answer them with.
+/// Ssh uses askpass only when `ssh_askpass` is set, otherwise it runs in batch
+/// mode, so authentication problems fail right away with the error of git or ssh
+/// even with OpenSSH older than 8.4, which ignores `SSH_ASKPASS_REQUIRE` and
+/// prompts on terminal. SSH key of the repository is added to `GIT_SSH_COMMAND`,
+/// ssh command of the user is kept, including `core.sshCommand` and `GIT_SSH`.
+/// Token header is passed as git config through environment, so it's neither in
+/// arguments nor in `.git/config`. Credential helpers configured in git are reset
+/// the same way, unless `credential_helper` is `git`.
+fn git_command(rc: &RunConfig, auth: &RepoAuth) -> Command {
+    let (username, password) = match auth.cred.as_deref() {
+        Some(c) => (&c.username, &c.password),
+        None => (&rc.git_username, &rc.git_password),
+    };
+    let answers = [
+        (askpass::ENV_USERNAME, username.as_str()),
+        (askpass::ENV_PASSWORD, password.expose()),
+        (askpass::ENV_PASSPHRASE, rc.ssh_askpass.expose()),
+    ];
+    let git_askpass = rc
+        .askpass
+        .as_ref()
+        .filter(|_| !username.is_empty() || !password.is_empty());
+    let ssh_askpass = rc.askpass.as_ref().filter(|_| !rc.ssh_askpass.is_empty());
+
+    let mut cmd = Command::new("git");
+    cmd.env(ENV_GIT_TERMINAL_PROMPT, "0")
+        .env(ENV_GCM_INTERACTIVE, "never")
+        .stdin(Stdio::null())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("Failed to execute child");
+        .stderr(Stdio::piped());
+    new_process_group(&mut cmd);
 
-    // No, I'm not going to use it
-    let _res = control_process(cmd, cd.to_str().unwrap_or(""), GitMode::FETCH).await;
+    if let Some(sc) = ssh::ssh_command(&auth.ssh_base, auth.ssh_key, ssh_askpass.is_none()) {
+        cmd.env(ENV_GIT_SSH_COMMAND, sc);
+    }
+
+    let mut config = Vec::<(&str, &str)>::with_capacity(2);
+    if !auth.git_helpers {
+        // Empty value resets helpers configured in git, so they neither answer
+        // with stale credentials nor store credentials passed by freshgit
+        config.push(("credential.helper", ""));
+    }
+    if let Some((key, value)) = &auth.header {
+        config.push((key, value.expose()));
+    }
+    // Config entries of the user's environment are kept
+    let n = std::env::var(ENV_GIT_CONFIG_COUNT)
+        .ok()
+        .and_then(|c| c.parse::<usize>().ok())
+        .unwrap_or(0);
+    for (i, (key, value)) in config.iter().enumerate() {
+        cmd.env(format!("GIT_CONFIG_KEY_{}", n + i), key)
+            .env(format!("GIT_CONFIG_VALUE_{}", n + i), value);
+    }
+    if !config.is_empty() {
+        cmd.env(ENV_GIT_CONFIG_COUNT, (n + config.len()).to_string());
+    }
+
+    match git_askpass {
+        Some(ap) => cmd.env(ENV_GIT_ASKPASS, ap),
+        // Empty GIT_ASKPASS takes precedence over core.askPass and is ignored
+        None => cmd.env(ENV_GIT_ASKPASS, ""),
+    };
+    match ssh_askpass {
+        Some(ap) => cmd
+            .env(ENV_SSH_ASKPASS, ap)
+            .env(ENV_SSH_ASKPASS_REQUIRE, "force"),
+        None => cmd
+            .env_remove(ENV_SSH_ASKPASS)
+            .env(ENV_SSH_ASKPASS_REQUIRE, "never"),
+    };
+    if git_askpass.or(ssh_askpass).is_some() {
+        cmd.env(askpass::ENV_ASKPASS, "1");
+    }
+    // Answers are never inherited from environment of freshgit
+    for (var, val) in answers {
+        match git_askpass.or(ssh_askpass).is_some() && !val.is_empty() {
+            true => cmd.env(var, val),
+            false => cmd.env_remove(var),
+        };
+    }
+
+    cmd
+}
+
+/// Makes git report progress when stall detection is on, otherwise long transfers
+/// are silent and could be taken for stalled.
+fn progress_arg(tl: &TimeLimits) -> Option<&'static str> {
+    tl.stall.map(|_| "--progress")
 }
 
-/// Tries to get stdout and stderr from running process and then checks them for
-/// clone or fetch failure to kill process and do not wait for user input
-/// this is usually needed when user password is incorrect, not accepted or
-/// repository does not exist at all, or cannot answer.
+/// Reads `origin` remote of the repository, so fetches are limited the same way as
+/// clones from its host.
+async fn origin_remote(cd: &Path) -> Option<RemoteAddr> {
+    let out = Command::new("git")
+        .current_dir(cd)
+        .args(["config", "--get", "remote.origin.url"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+
+    RemoteAddr::parse(&String::from_utf8_lossy(&out.stdout)).ok()
+}
+
+/// Resolves commit reported as HEAD of the repository: upstream of the current branch
+/// for fetch, since fetch doesn't move HEAD itself, and HEAD for clone.
+async fn head_commit(cd: &Path, mode: GitMode) -> Option<String> {
+    let revs: &[&str] = match mode {
+        GitMode::FETCH => &["@{upstream}", "HEAD"],
+        GitMode::CLONE => &["HEAD"],
+    };
+
+    for rev in revs {
+        let out = Command::new("git")
+            .current_dir(cd)
+            .args(["rev-parse", "--verify", "--quiet", rev])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .await
+            .ok()?;
+        if out.status.success() {
+            return Some(String::from_utf8_lossy(&out.stdout).trim().to_string());
+        }
+    }
+
+    None
+}
+
+/// Lists all the refs of the repository to check if fetch has changed anything.
+async fn refs_snapshot(cd: &Path) -> Option<Vec<u8>> {
+    let out = Command::new("git")
+        .current_dir(cd)
+        .args(["for-each-ref", "--format=%(objectname) %(refname)"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .ok()?;
+
+    match out.status.success() {
+        true => Some(out.stdout),
+        false => None,
+    }
+}
+
+/// Reads stdout and stderr of running process, killing it when it asks for input
+/// nobody can give, e.g. passphrase of the key or password which was not
+/// provided. Process is also killed when it runs or stalls for longer than
+/// allowed by time limits.
 async fn control_process(
     mut cmd: tokio::process::Child,
     repo: &str,
     mode: GitMode,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let pid = cmd.id().unwrap();
+    tl: TimeLimits,
+) -> Result<GitRun, Box<dyn std::error::Error + Send + Sync>> {
+    let pid = cmd.id().unwrap_or_default();
     let stdout = cmd.stdout.take().expect("no stdout");
     let stderr = cmd.stderr.take().expect("no stderr");
-    let reader_out = BufReader::new(stdout);
-    let reader_err = BufReader::new(stderr);
+    let (tx, rx) = unbounded_channel();
+    let readers = [
+        tokio::task::spawn(read_output(
+            BufReader::new(stdout),
+            Stream::Stdout,
+            tx.clone(),
+        )),
+        tokio::task::spawn(read_output(BufReader::new(stderr), Stream::Stderr, tx)),
+    ];
+
+    let mut stderr = VecDeque::<String>::with_capacity(STDERR_TAIL);
+
+    let supervise = async {
+        let check = check_process(rx, repo, tl.stall, &mut stderr);
+        tokio::pin!(check);
+        // Children of git, e.g. ssh multiplexing master, can keep the pipes open
+        // after git exits, so exit of git is awaited together with its output
+        let finished = tokio::select! {
+            stopped = &mut check => stopped,
+            status = cmd.wait() => Some(Finished::Exited(status?)),
+        };
+        match finished {
+            Some(Finished::Exited(status)) => {
+                // Last lines written before exit can still be in the pipes
+                let _ = tokio::time::timeout(OUTPUT_DRAIN, check).await;
+                Ok(Finished::Exited(status))
+            }
+            Some(stopped) => Ok(stopped),
+            None => Ok(Finished::Exited(cmd.wait().await?)),
+        }
+    };
+    let finished = match tl.total {
+        Some(t) => match tokio::time::timeout(t, supervise).await {
+            Ok(f) => f,
+            Err(_) => Ok(Finished::TimedOut(t)),
+        },
+        None => supervise.await,
+    };
+
+    match &finished {
+        Ok(Finished::Exited(status)) => match mode {
+            GitMode::FETCH => info!("Finished fetching: {} {}", repo, status),
+            GitMode::CLONE => info!("Finished cloning: {} {}", repo, status),
+        },
+        _ => {
+            warn!("Killing process: {} {}", repo, pid);
+            kill_process_group(pid);
+            if let Err(e) = cmd.kill().await {
+                error!("Could not kill process {}: {}", pid, e);
+            }
+        }
+    }
+
+    // Children which left process group of git could still hold the pipes open
+    for rd in readers {
+        rd.abort();
+    }
+
+    finished.map(|f| GitRun {
+        finished: f,
+        stderr: stderr.into(),
+    })
+}
+
+/// Runs git in its own process group, so ssh, credential helpers and other
+/// children of git can be killed together with it.
+#[cfg(unix)]
+pub fn new_process_group(cmd: &mut Command) {
+    // setpgid is async-signal-safe, so it can be called between fork and exec
+    unsafe {
+        cmd.pre_exec(|| match libc::setpgid(0, 0) {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn new_process_group(_cmd: &mut Command) {}
+
+/// Kills every process of the group git leads, killing git alone would leave
+/// stuck ssh transport or credential helper running.
+#[cfg(unix)]
+pub fn kill_process_group(pid: u32) {
+    let pgid = match libc::pid_t::try_from(pid) {
+        Ok(p) if p > 0 => p,
+        _ => return,
+    };
+    if unsafe { libc::killpg(pgid, libc::SIGKILL) } != 0 {
+        error!(
+            "Could not kill process group {}: {}",
+            pgid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill_process_group(_pid: u32) {}
+
+/// Running process group, killed if freshgit is interrupted while it's alive.
+pub struct Running(u32);
+
+impl Running {
+    /// Registers process group led by `pid`, folder `clean` is removed after the
+    /// group is killed on interrupt.
+    pub fn new(pid: u32, clean: Option<&Path>) -> Running {
+        if pid != 0 {
+            RUNNING
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(pid, clean.map(Path::to_path_buf));
+        }
+        Running(pid)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.0);
+    }
+}
 
-    tokio::task::spawn(async move {
-        let status = cmd.wait().await.expect("Process failed");
-        info!("Finished process: {}", status);
+/// Kills every running process group and removes clone folders they were
+/// creating, partial clones would be taken for cloned repositories otherwise.
+fn kill_running() {
+    let running = RUNNING.lock().unwrap_or_else(|e| e.into_inner());
+    for pid in running.keys() {
+        kill_process_group(*pid);
+    }
+    for cl in running.values().flatten() {
+        if let Err(e) = std::fs::remove_dir_all(cl) {
+            error!("Could not remove {}: {}", cl.to_string_lossy(), e);
+        }
+    }
+}
+
+/// Starts thread killing running git processes when freshgit gets SIGINT or
+/// SIGTERM, git runs in its own process group and wouldn't get the signal of the
+/// terminal. Freshgit exits right after it, the thread is started only once.
+#[cfg(unix)]
+fn watch_signals() {
+    use tokio::signal::unix::{signal, SignalKind};
+    static WATCH: std::sync::Once = std::sync::Once::new();
+
+    WATCH.call_once(|| {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        // Handlers are registered before returning, so no signal is missed
+        let signals = {
+            let _rt = rt.enter();
+            signal(SignalKind::interrupt()).and_then(|i| Ok((i, signal(SignalKind::terminate())?)))
+        };
+        let (mut int, mut term) = match signals {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Git won't be stopped on interrupt: {}", e);
+                return;
+            }
+        };
+
+        std::thread::spawn(move || {
+            let signo = rt.block_on(async {
+                tokio::select! {
+                    _ = int.recv() => libc::SIGINT,
+                    _ = term.recv() => libc::SIGTERM,
+                }
+            });
+            warn!("Interrupted, killing running git processes");
+            kill_running();
+            // Shells report process killed by signal the same way
+            exit(128 + signo);
+        });
     });
+}
+
+#[cfg(not(unix))]
+fn watch_signals() {}
+
+/// Sends every line of git output to the channel until the stream is closed.
+/// Progress lines ending with carriage return are sent as separate lines, and
+/// unfinished line is sent when nothing follows it for a while, since prompts
+/// don't end with newline.
+async fn read_output<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    stream: Stream,
+    tx: UnboundedSender<(Stream, String)>,
+) {
+    let mut line = Vec::<u8>::with_capacity(256);
+    loop {
+        let buf = match line.is_empty() {
+            true => reader.fill_buf().await,
+            false => match tokio::time::timeout(PROMPT_WAIT, reader.fill_buf()).await {
+                Ok(b) => b,
+                Err(_) => {
+                    let l = String::from_utf8_lossy(&line).trim_end().to_string();
+                    line.clear();
+                    if !l.is_empty() && tx.send((stream, l)).is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            },
+        };
+        let buf = match buf {
+            Ok(b) if !b.is_empty() => b,
+            _ => break,
+        };
+
+        let (used, eol) = match buf.iter().position(|b| *b == b'\n' || *b == b'\r') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        line.extend_from_slice(&buf[..used]);
+        reader.consume(used);
 
-    check_process(pid, reader_out, reader_err, &mode, repo).await
+        if eol {
+            let l = String::from_utf8_lossy(&line).trim_end().to_string();
+            line.clear();
+            if !l.is_empty() && tx.send((stream, l)).is_err() {
+                return;
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        let _ = tx.send((
+            stream,
+            String::from_utf8_lossy(&line).trim_end().to_string(),
+        ));
+    }
 }
 
-/// Continuously parses stdout and stderr from the Command to detect if it is
-/// running correctly, otherwise sends kill -9 for this pid.
+/// Continuously checks output of the process for prompts it would block on.
+/// Last lines of stderr are kept in `stderr`, stdout is only logged. Returns when
+/// both streams are closed, when prompt was found, or when there was no output
+/// for `stall` time.
 async fn check_process(
-    pid: u32,
-    reader_out: BufReader<tokio::process::ChildStdout>,
-    reader_err: BufReader<tokio::process::ChildStderr>,
-    mode: &GitMode,
+    mut rx: UnboundedReceiver<(Stream, String)>,
     repo: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut reader_o = reader_out.lines();
-    let mut reader_e = reader_err.lines();
-
-    while let (Some(o), Some(e)) = (reader_o.next_line().await?, reader_e.next_line().await?) {
-        // debug!("Stdout: {}; Stderr: {}", o, e);
-        if HashSet::from_iter(o.split([' ', ':']))
-            .intersection(&GIT_OUT)
-            .count()
-            > 0
-            || HashSet::from_iter(e.split([' ', ':']))
-                .intersection(&GIT_OUT)
-                .count()
-                > 0
-        {
-            match &mode {
-                GitMode::FETCH => {
-                    warn!("Problem fetching: {}", &repo);
+    stall: Option<Duration>,
+    stderr: &mut VecDeque<String>,
+) -> Option<Finished> {
+    loop {
+        let line = match stall {
+            Some(st) => match tokio::time::timeout(st, rx.recv()).await {
+                Ok(l) => l,
+                Err(_) => {
+                    warn!("No output for {}s: {}", st.as_secs(), &repo);
+                    return Some(Finished::Stalled(st));
                 }
-                GitMode::CLONE => {
-                    warn!("Problem cloning: {}", &repo);
+            },
+            None => rx.recv().await,
+        };
+        let (stream, l) = line?;
+        let prompt = GitError::is_prompt(&l);
+
+        match stream {
+            Stream::Stdout => debug!("{}: {}", &repo, secret::redact_urls(&l)),
+            Stream::Stderr => {
+                if stderr.len() == STDERR_TAIL {
+                    stderr.pop_front();
                 }
+                stderr.push_back(secret::redact_urls(&l));
             }
+        }
 
-            // Kill the process
-            let _kill = Command::new("kill").arg("-9").arg(pid.to_string()).output();
-            info!("Killed process: {}", pid);
+        if prompt {
+            warn!("Git is waiting for input: {}", &repo);
+            return Some(Finished::Prompted);
         }
     }
-
-    Ok(())
 }
 
-/// Reads repo lists from provided files. Text files with repository on every line and
-/// csv file with "repositories" column are supported at the moment.
-fn read_repo_lists(sd: &PathBuf, fl: Vec<PathBuf>) -> Vec<(Url, PathBuf)> {
-    let mut url_vs_folder = Vec::<(Url, PathBuf)>::with_capacity(2048);
+/// Reads repo lists from provided files, lists of unsupported formats are skipped.
+/// Returns `None` if any of the lists could not be read, so nothing is cloned from
+/// a partially understood configuration.
+fn read_repo_lists(sd: &Path, fl: Vec<PathBuf>, cc: &str) -> Option<Vec<(RemoteAddr, PathBuf)>> {
+    let mut url_vs_folder = Vec::<(RemoteAddr, PathBuf)>::with_capacity(2048);
 
     for f in fl {
-        if f.exists() {
-            let ext = f.extension().unwrap_or(OsStr::new(""));
-            if let Some(li) = read_lists(sd, &f, &ext) {
-                url_vs_folder.extend(li);
-            }
+        if !f.exists() {
+            continue;
         }
+        if !lists::is_supported(&f) {
+            warn!("Skipping list of unsupported format: {}", f.display());
+            continue;
+        }
+        url_vs_folder.extend(read_lists(sd, &f, cc)?);
     }
 
-    url_vs_folder
+    Some(url_vs_folder)
 }
 
-/// Reads provided text file and converts every supported entry to pair of
-/// (Entry_URL, Folder_Addr) pushed into Vec.
-fn read_lists(sd: &PathBuf, txt: &PathBuf, ext: &OsStr) -> Option<Vec<(Url, PathBuf)>> {
-    let repo_path = sd.clone();
-
-    let file = match File::open(&txt) {
-        Ok(fi) => fi,
+/// Reads provided list and converts every supported entry to pair of
+/// (Entry_URL, Folder_Addr) pushed into Vec. Entries which can't be parsed are
+/// skipped with a warning, while unreadable files and csv files without the
+/// requested column return `None`.
+fn read_lists(sd: &Path, txt: &Path, cc: &str) -> Option<Vec<(RemoteAddr, PathBuf)>> {
+    let entries = match lists::read_list(txt, cc) {
+        Ok(en) => en,
         Err(e) => {
-            error!("Could not read: {} {}", &txt.to_str().unwrap(), e);
+            error!("{}", e);
             return None;
         }
     };
 
-    let mut url_vs_folder = Vec::<(Url, PathBuf)>::with_capacity(4096);
-
-    if ext == "txt" || ext == "" {
-        let reader = std::io::BufReader::new(file);
-        let lines: Vec<String> = reader
-            .lines()
-            .map(|l| l.unwrap_or("".to_string()))
-            .collect();
-
-        for l in lines.into_iter() {
-            // debug!("String URL (txt): {}", l);
-            let url: Url = match Url::parse(&l) {
-                Ok(u) => u,
-                Err(e) => {
-                    warn!("Could not parse url: {} {}", l, e);
-                    continue;
-                }
-            };
-
-            let mut cwd = repo_path.clone();
-            // https://some.site.com/author/repository - basically 2 segments are
-            // present, but there can be other cases, then path will be longer
-            let mut url_segments: Vec<String> =
-                url.path().split('/').map(|i| i.to_string()).collect();
-            let idx_last = url_segments.len() - 1;
-            // Cut ".git" from the end of path
-            if url_segments.get(idx_last).unwrap().ends_with(".git") {
-                url_segments[idx_last] =
-                    url_segments.get_mut(idx_last).unwrap().replace(".git", "");
+    let url_vs_folder = entries
+        .into_iter()
+        .filter_map(|en| match en.remote {
+            Ok(remote) => {
+                let cwd = remote.target_dir(sd);
+                Some((remote, cwd))
             }
+            Err(e) => {
+                warn!("{} (line {} of {})", e, en.line, txt.display());
+                None
+            }
+        })
+        .collect();
+
+    Some(url_vs_folder)
+}
 
-            cwd.extend(url_segments.into_iter());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
 
-            url_vs_folder.push((url, cwd));
+    fn run_config(password: &str, passphrase: &str) -> RunConfig {
+        RunConfig {
+            git_username: String::new(),
+            git_password: Secret::new(password.to_string()),
+            ssh_askpass: Secret::new(passphrase.to_string()),
+            askpass: Some(PathBuf::from("/bin/freshgit")),
+            credentials: Credentials::new(None, &HashMap::new()),
+            ssh_keys: SshKeys::new(&HashMap::new()),
+            timeouts: Timeouts::new(None, None, &HashMap::new()),
+            retries: Retries::new(0, 0),
         }
-    } else if ext == "csv" {
-        let mut reader = ReaderBuilder::new().has_headers(true).from_reader(file);
-        let headers = match reader.headers() {
-            Ok(hs) => hs,
-            Err(e) => {
-                warn!("Could not get header from csv: {}", e);
-                return None;
-            }
+    }
+
+    /// Environment git is started with, `None` for removed variables.
+    fn env(cmd: &Command) -> HashMap<String, Option<String>> {
+        cmd.as_std()
+            .get_envs()
+            .map(|(k, v)| {
+                let s = |s: &OsStr| s.to_string_lossy().to_string();
+                (s(k), v.map(s))
+            })
+            .collect()
+    }
+
+    fn auth() -> RepoAuth<'static> {
+        RepoAuth {
+            ssh_base: "ssh".to_string(),
+            ..RepoAuth::default()
+        }
+    }
+
+    #[test]
+    fn removes_answers_without_askpass() {
+        let env = env(&git_command(&run_config("", ""), &auth()));
+        let var = |k: &str| env.get(k).cloned();
+
+        assert_eq!(var(ENV_GIT_TERMINAL_PROMPT), Some(Some("0".to_string())));
+        assert_eq!(var(ENV_GIT_ASKPASS), Some(Some(String::new())));
+        assert_eq!(var(ENV_SSH_ASKPASS), Some(None));
+        assert_eq!(
+            var(ENV_SSH_ASKPASS_REQUIRE),
+            Some(Some("never".to_string()))
+        );
+        assert_eq!(var(askpass::ENV_ASKPASS), None);
+        for a in [
+            askpass::ENV_USERNAME,
+            askpass::ENV_PASSWORD,
+            askpass::ENV_PASSPHRASE,
+        ] {
+            assert_eq!(var(a), Some(None));
+        }
+        assert!(var(ENV_GIT_SSH_COMMAND)
+            .flatten()
+            .unwrap()
+            .contains("BatchMode=yes"));
+    }
+
+    #[test]
+    fn passes_answers_to_askpass() {
+        let env = env(&git_command(&run_config("pass", "phrase"), &auth()));
+        let var = |k: &str| env.get(k).cloned().flatten();
+
+        assert_eq!(var(ENV_GIT_ASKPASS).as_deref(), Some("/bin/freshgit"));
+        assert_eq!(var(ENV_SSH_ASKPASS).as_deref(), Some("/bin/freshgit"));
+        assert_eq!(var(ENV_SSH_ASKPASS_REQUIRE).as_deref(), Some("force"));
+        assert_eq!(var(askpass::ENV_ASKPASS).as_deref(), Some("1"));
+        assert_eq!(var(askpass::ENV_PASSWORD).as_deref(), Some("pass"));
+        assert_eq!(var(askpass::ENV_PASSPHRASE).as_deref(), Some("phrase"));
+        assert_eq!(env.get(askpass::ENV_USERNAME), Some(&None));
+        assert_eq!(var(ENV_GIT_SSH_COMMAND), None);
+    }
+
+    #[test]
+    fn resets_credential_helpers() {
+        let env = env(&git_command(&run_config("", ""), &auth()));
+        let var = |k: &str| env.get(k).cloned().flatten();
+
+        assert_eq!(var(ENV_GIT_CONFIG_COUNT).as_deref(), Some("1"));
+        assert_eq!(
+            var("GIT_CONFIG_KEY_0").as_deref(),
+            Some("credential.helper")
+        );
+        assert_eq!(var("GIT_CONFIG_VALUE_0").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn keeps_git_helpers_with_token_header() {
+        let auth = RepoAuth {
+            git_helpers: true,
+            header: Some((
+                "http.https://host/.extraHeader".to_string(),
+                Secret::new("Authorization: Basic dTpw".to_string()),
+            )),
+            ..auth()
         };
+        let first = env(&git_command(&run_config("", ""), &auth));
+        let var = |k: &str| first.get(k).cloned().flatten();
 
-        // There is possibility url entries won't be found, but csv file will be
-        // read anyway, maybe it's better to check "repository" or any other
-        // record is in header
-        let repo_pos = headers
-            .iter()
-            .position(|he| he.to_string() == "repository".to_string())
-            .unwrap_or_default();
-
-        let mut url_vs_folder = Vec::<(Url, PathBuf)>::with_capacity(512);
-        for rec in reader.into_records() {
-            let r = match rec {
-                Ok(re) => re,
-                Err(e) => {
-                    warn!("Could not get record: {}", e);
-                    continue;
-                }
-            };
-            if r.is_empty() {
-                continue;
-            }
+        assert_eq!(var(ENV_GIT_CONFIG_COUNT).as_deref(), Some("1"));
+        assert_eq!(
+            var("GIT_CONFIG_KEY_0").as_deref(),
+            Some("http.https://host/.extraHeader")
+        );
+        assert_eq!(
+            var("GIT_CONFIG_VALUE_0").as_deref(),
+            Some("Authorization: Basic dTpw")
+        );
 
-            let repo = match r.get(repo_pos) {
-                Some(re) => re,
-                None => {
-                    warn!("Could not get record element");
-                    continue;
-                }
-            };
+        let auth = RepoAuth {
+            git_helpers: false,
+            ..auth
+        };
+        let second = env(&git_command(&run_config("", ""), &auth));
+        let var = |k: &str| second.get(k).cloned().flatten();
+        assert_eq!(var(ENV_GIT_CONFIG_COUNT).as_deref(), Some("2"));
+        assert_eq!(
+            var("GIT_CONFIG_KEY_0").as_deref(),
+            Some("credential.helper")
+        );
+        assert_eq!(
+            var("GIT_CONFIG_KEY_1").as_deref(),
+            Some("http.https://host/.extraHeader")
+        );
+    }
 
-            // debug!("String URL (csv): {}", repo);
-            let url: Url = match Url::parse(repo) {
-                Ok(ur) => ur,
-                Err(e) => {
-                    warn!("Could not parse url: {} {}", repo, e);
-                    continue;
-                }
-            };
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn kills_running_groups_and_removes_clones() {
+        use std::os::unix::process::ExitStatusExt;
 
-            let mut cwd = repo_path.clone();
-            let mut url_segments: Vec<String> =
-                url.path().split('/').map(|i| i.to_string()).collect();
-            let idx_last = url_segments.len() - 1;
-            // Cut ".git" from the end of path
-            if url_segments.get(idx_last).unwrap().ends_with(".git") {
-                url_segments[idx_last] = url_segments.get(idx_last).unwrap().replace(".git", "");
-            }
-            // See read_list_txt
-            cwd.extend(url_segments.into_iter());
+        let cl = std::env::temp_dir().join(format!("freshgit-{}-interrupted", std::process::id()));
+        std::fs::create_dir_all(cl.join(".git")).unwrap();
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 20 & wait"]);
+        new_process_group(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
 
-            url_vs_folder.push((url, cwd));
-        }
+        let running = Running::new(child.id().unwrap(), Some(&cl));
+        kill_running();
+        let status = child.wait().await.unwrap();
+        drop(running);
+
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(!cl.exists());
+        assert!(RUNNING.lock().unwrap().is_empty());
     }
 
-    if url_vs_folder.len() > 0 {
-        Some(url_vs_folder)
-    } else {
-        None
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn doesnt_wait_for_pipes_of_children() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "echo 'fatal: gone' >&2; setsid sleep 20 & exit 3"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let started = Instant::now();
+
+        let run = control_process(
+            cmd.spawn().unwrap(),
+            "r",
+            GitMode::FETCH,
+            TimeLimits::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(run.finished, Finished::Exited(s) if s.code() == Some(3)));
+        assert_eq!(run.stderr, vec!["fatal: gone".to_string()]);
     }
 }
@@ -0,0 +1,386 @@
+//! Interactive wizard writing a new config. Every answer is checked before the
+//! next question, so the written config can be used right away: source folder has
+//! to exist and be writable, and repository lists have to be readable. Secrets
+//! are never asked for, only names of environment variables holding them.
+use crate::check::check_writable;
+use crate::config_file::{Format, LOCAL_CONFIG};
+use crate::error::FreshgitError;
+use crate::lists;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Default column of csv lists.
+const CSV_COLUMN: &str = "repository";
+/// Default amount of git processes run at once.
+const MAX_JOBS: usize = 8;
+/// Credential strategies offered by the wizard.
+const STRATEGIES: [&str; 4] = ["none", "git", "helper", "env"];
+
+/// Answers of the wizard.
+struct Answers {
+    src_folder: PathBuf,
+    files_to_read: Vec<PathBuf>,
+    csv_column: Option<String>,
+    max_jobs: usize,
+    credentials: Strategy,
+}
+
+/// Credential strategy with its answers.
+enum Strategy {
+    /// Ssh agent or public repositories.
+    None,
+    /// Helpers configured in git itself.
+    Git,
+    Helper(String),
+    /// Password in environment variable `var`.
+    Env {
+        username: String,
+        var: String,
+    },
+}
+
+/// Asks questions on stdin and writes commented toml config to `path`, by
+/// default `./freshgit.toml` which is found without `-c/--config`.
+pub fn init(path: Option<&Path>) -> Result<(), FreshgitError> {
+    let path = path.unwrap_or(Path::new(LOCAL_CONFIG));
+    let write_err = |message: String| FreshgitError::ConfigWrite {
+        path: path.to_path_buf(),
+        message,
+    };
+    if Format::from_path(path) != Format::Toml {
+        return Err(write_err(
+            "only toml config can keep comments, use config convert for other formats".to_string(),
+        ));
+    }
+    if path.exists() {
+        return Err(write_err("file already exists".to_string()));
+    }
+
+    println!("Creating config {}", path.display());
+    let answers = ask_all().map_err(|e| write_err(format!("no answer: {}", e)))?;
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut f| f.write_all(render(&answers).as_bytes()))
+        .map_err(|e| write_err(e.to_string()))?;
+    println!("Config is written to {}", path.display());
+
+    Ok(())
+}
+
+fn ask_all() -> io::Result<Answers> {
+    let src_folder = ask_valid("Folder repositories are cloned to", Some("."), |a| {
+        let folder = fs::canonicalize(a).map_err(|e| format!("{}: {}", a, e))?;
+        if !folder.is_dir() {
+            return Err(format!("{} is not a folder", a));
+        }
+        check_writable(&folder).map_err(|e| format!("{} is not writable: {}", a, e))?;
+        Ok(folder)
+    })?;
+
+    let files_to_read = ask_valid(
+        "Repository lists, .txt or .csv, comma separated (empty to only update)",
+        Some(""),
+        |a| {
+            a.split(',')
+                .map(|f| f.trim())
+                .filter(|f| !f.is_empty())
+                .map(check_list)
+                .collect::<Result<Vec<PathBuf>, String>>()
+        },
+    )?;
+    let csv_lists: Vec<&PathBuf> = files_to_read.iter().filter(|f| is_csv(f)).collect();
+    let csv_column = match csv_lists.is_empty() {
+        true => None,
+        false => Some(ask_valid(
+            "Column of csv lists with repositories",
+            Some(CSV_COLUMN),
+            |a| {
+                for f in csv_lists.iter() {
+                    lists::read_list(f, a)?;
+                }
+                Ok(a.to_string())
+            },
+        )?),
+    };
+    show_lists(&files_to_read, csv_column.as_deref().unwrap_or(CSV_COLUMN));
+
+    let max_jobs = ask_valid(
+        "Git processes to run at once",
+        Some(&MAX_JOBS.to_string()),
+        |a| match a.parse::<usize>() {
+            Ok(j) if j > 0 => Ok(j),
+            _ => Err(format!("{} is not a number above 0", a)),
+        },
+    )?;
+
+    let strategy = ask_valid(
+        "Credentials: none (ssh agent or public repositories), git (helpers configured \
+         in git), helper (another credential helper) or env (password in environment \
+         variable)",
+        Some(STRATEGIES[0]),
+        |a| match STRATEGIES.contains(&a) {
+            true => Ok(a.to_string()),
+            false => Err(format!("{} is not one of {}", a, STRATEGIES.join(", "))),
+        },
+    )?;
+    let credentials = ask_credentials(&strategy)?;
+
+    Ok(Answers {
+        src_folder,
+        files_to_read,
+        csv_column,
+        max_jobs,
+        credentials,
+    })
+}
+
+/// Checks that the list exists and has supported format, text lists are read
+/// right away, csv lists are read once their column is known.
+fn check_list(file: &str) -> Result<PathBuf, String> {
+    let path = fs::canonicalize(file).map_err(|e| format!("{}: {}", file, e))?;
+    if !lists::is_supported(&path) {
+        return Err(format!("{} is not .txt or .csv file", file));
+    }
+    if !is_csv(&path) {
+        lists::read_list(&path, CSV_COLUMN)?;
+    }
+
+    Ok(path)
+}
+
+fn is_csv(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "csv")
+}
+
+/// Shows amount of repositories of every list and entries which can't be parsed.
+fn show_lists(files: &[PathBuf], cc: &str) {
+    for f in files {
+        let entries = match lists::read_list(f, cc) {
+            Ok(en) => en,
+            Err(e) => {
+                println!("  {}", e);
+                continue;
+            }
+        };
+        let valid = entries.iter().filter(|en| en.remote.is_ok()).count();
+        println!("  {}: {} repositories", f.display(), valid);
+        for en in entries.iter() {
+            if let Err(e) = &en.remote {
+                println!("  line {} is skipped: {}", en.line, e);
+            }
+        }
+    }
+}
+
+fn ask_credentials(strategy: &str) -> io::Result<Strategy> {
+    let strategy = match strategy {
+        "git" => Strategy::Git,
+        "helper" => Strategy::Helper(ask_valid(
+            "Credential helper, e.g. store or cache",
+            None,
+            |a| Ok(a.to_string()),
+        )?),
+        "env" => Strategy::Env {
+            username: ask_valid("Git username", None, |a| Ok(a.to_string()))?,
+            var: ask_valid(
+                "Environment variable with password or token",
+                Some("GIT_PASSWORD"),
+                |a| match a.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                    true => Ok(a.to_string()),
+                    false => Err(format!("{} is not valid variable name", a)),
+                },
+            )?,
+        },
+        _ => Strategy::None,
+    };
+
+    Ok(strategy)
+}
+
+/// Config lines with credentials and the comment describing them.
+fn credential_lines(strategy: &Strategy) -> Vec<String> {
+    match strategy {
+        Strategy::Git => vec![
+            "# Https credentials are taken from helpers configured in git".to_string(),
+            format!("credential_helper = {}", quote("git")),
+        ],
+        Strategy::Helper(helper) => vec![
+            "# Https credentials are taken from credential helper".to_string(),
+            format!("credential_helper = {}", quote(helper)),
+        ],
+        Strategy::Env { username, var } => vec![
+            "# Password is read from environment when freshgit starts".to_string(),
+            format!("git_username = {}", quote(username)),
+            format!("git_password = {}", quote(&format!("${{env:{}}}", var))),
+        ],
+        Strategy::None => vec!["# No credentials, ssh agent is used for ssh remotes".to_string()],
+    }
+}
+
+/// Asks until `check` accepts the answer, empty answer takes `default`.
+fn ask_valid<T, F: Fn(&str) -> Result<T, String>>(
+    question: &str,
+    default: Option<&str>,
+    check: F,
+) -> io::Result<T> {
+    let stdin = io::stdin();
+    loop {
+        match default {
+            Some(d) if !d.is_empty() => print!("{} [{}]: ", question, d),
+            _ => print!("{}: ", question),
+        }
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        if stdin.lock().read_line(&mut answer)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let answer = match (answer.trim(), default) {
+            ("", Some(d)) => d,
+            ("", None) => {
+                println!("  answer is required");
+                continue;
+            }
+            (a, _) => a,
+        };
+        match check(answer) {
+            Ok(v) => return Ok(v),
+            Err(e) => println!("  {}", e),
+        }
+    }
+}
+
+/// Quotes text as toml string.
+fn quote(text: &str) -> String {
+    toml::Value::String(text.to_string()).to_string()
+}
+
+fn render(a: &Answers) -> String {
+    let lists: Vec<String> = a
+        .files_to_read
+        .iter()
+        .map(|f| quote(&f.to_string_lossy()))
+        .collect();
+    let mut out = vec![
+        "# Config of freshgit, written by `freshgit init`.".to_string(),
+        "# Values can be overridden for a single run with flags, FRESHGIT_* environment"
+            .to_string(),
+        "# variables and --set key=value.".to_string(),
+        String::new(),
+        "# Folder repositories are cloned to, path of the address is kept, so".to_string(),
+        "# git@host:owner/repo.git is cloned to <src_folder>/owner/repo.".to_string(),
+        format!("src_folder = {}", quote(&a.src_folder.to_string_lossy())),
+        String::new(),
+        "# Repository lists: text files with a repository per line, or csv files with".to_string(),
+        "# repositories in csv_column.".to_string(),
+        format!("files_to_read = [{}]", lists.join(", ")),
+    ];
+    if let Some(cc) = &a.csv_column {
+        out.push(format!("csv_column = {}", quote(cc)));
+    }
+    out.extend([
+        String::new(),
+        "# Amount of git processes run at once.".to_string(),
+        format!("async_exec = {}", a.max_jobs > 1),
+        format!("max_jobs = {}", a.max_jobs),
+        String::new(),
+    ]);
+    out.extend(credential_lines(&a.credentials));
+    out.extend([
+        String::new(),
+        "# Git is killed after timeout_secs, or if it has no output for".to_string(),
+        "# stall_timeout_secs. Network failures are retried with growing delay.".to_string(),
+        "# timeout_secs = 600".to_string(),
+        "# stall_timeout_secs = 120".to_string(),
+        "# retries = 2".to_string(),
+        "# retry_delay_ms = 1000".to_string(),
+        String::new(),
+        "# Limits and credentials of single hosts.".to_string(),
+        "# [hosts.\"github.com\"]".to_string(),
+        "# max_jobs = 4".to_string(),
+        "# delay_ms = 100".to_string(),
+        "# ssh_key = { identity = \"~/.ssh/id_ed25519\" }".to_string(),
+    ]);
+
+    out.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_file;
+    use crate::dl_upd::Config;
+
+    /// Renders config with `credentials`, writes it and reads it back.
+    fn round_trip(name: &str, credentials: Strategy) -> Config {
+        let answers = Answers {
+            src_folder: PathBuf::from("/src/it's \"here\""),
+            files_to_read: vec![PathBuf::from("/lists/a.txt"), PathBuf::from("/lists/b.csv")],
+            csv_column: Some("repo url".to_string()),
+            max_jobs: 4,
+            credentials,
+        };
+        let path = std::env::temp_dir().join(format!(
+            "freshgit-{}-init-{}.toml",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, render(&answers)).unwrap();
+        let conf = config_file::read::<Config>(&path);
+        fs::remove_file(&path).unwrap();
+
+        let conf = conf.unwrap();
+        assert_eq!(conf.src_folder, Some(answers.src_folder));
+        assert_eq!(conf.files_to_read, Some(answers.files_to_read));
+        assert_eq!(conf.csv_column, answers.csv_column);
+        assert_eq!(conf.async_exec, Some(true));
+        assert_eq!(conf.max_jobs, Some(4));
+        conf
+    }
+
+    #[test]
+    fn renders_config_without_credentials() {
+        let conf = round_trip("none", Strategy::None);
+        assert_eq!(conf.credential_helper, None);
+        assert_eq!(conf.git_username, None);
+        assert!(conf.git_password.is_none());
+    }
+
+    #[test]
+    fn renders_config_with_git_helpers() {
+        let conf = round_trip("git", Strategy::Git);
+        assert_eq!(conf.credential_helper.as_deref(), Some("git"));
+    }
+
+    #[test]
+    fn renders_config_with_helper() {
+        let conf = round_trip(
+            "helper",
+            Strategy::Helper("!pass-git-helper -m 'x'".to_string()),
+        );
+        assert_eq!(
+            conf.credential_helper.as_deref(),
+            Some("!pass-git-helper -m 'x'")
+        );
+    }
+
+    #[test]
+    fn renders_config_with_env_password() {
+        let conf = round_trip(
+            "env",
+            Strategy::Env {
+                username: "user".to_string(),
+                var: "GIT_TOKEN".to_string(),
+            },
+        );
+        assert_eq!(conf.git_username.as_deref(), Some("user"));
+        assert_eq!(
+            conf.git_password.as_ref().map(|p| p.expose()),
+            Some("${env:GIT_TOKEN}")
+        );
+    }
+}
@@ -0,0 +1,297 @@
+//! Limits amount of git processes running at once, globally and per remote host,
+//! keeps configured delay between requests to the same host, limits time every
+//! git process is allowed to run and decides when failed process is retried.
+use crate::dl_upd::HostConfig;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep_until, Instant};
+
+/// Limits of a single remote host.
+struct HostLimit {
+    jobs: Option<Arc<Semaphore>>,
+    delay: Duration,
+    next_start: Mutex<Instant>,
+}
+
+/// Slot to run a single git process, released on drop.
+pub struct Permit {
+    _global: OwnedSemaphorePermit,
+    _host: Option<OwnedSemaphorePermit>,
+}
+
+pub struct Limiter {
+    jobs: Arc<Semaphore>,
+    hosts: HashMap<String, HostLimit>,
+}
+
+impl Limiter {
+    /// Creates limiter allowing `mj` processes at once, hosts are limited additionally
+    /// using `max_jobs` and `delay_ms` from their entries in config.
+    pub fn new(mj: usize, hosts: &HashMap<String, HostConfig>) -> Limiter {
+        let hosts = hosts
+            .iter()
+            .map(|(h, hc)| {
+                let hl = HostLimit {
+                    jobs: hc.max_jobs.map(|j| Arc::new(Semaphore::new(j))),
+                    delay: Duration::from_millis(hc.delay_ms.unwrap_or(0)),
+                    next_start: Mutex::new(Instant::now()),
+                };
+                (h.to_lowercase(), hl)
+            })
+            .collect();
+
+        Limiter {
+            jobs: Arc::new(Semaphore::new(mj)),
+            hosts,
+        }
+    }
+
+    /// Waits until process for the `host` is allowed to start. Host slot is taken
+    /// before the global one, so repositories of a busy host don't hold global slots
+    /// other hosts could use. Delay of the host is kept after both slots are taken,
+    /// otherwise processes waiting for a global slot would start back-to-back.
+    pub async fn acquire(&self, host: Option<&str>) -> Permit {
+        let hl = host.and_then(|h| self.hosts.get(&h.to_lowercase()));
+
+        let host_permit = match hl.and_then(|hl| hl.jobs.clone()) {
+            Some(sem) => Some(sem.acquire_owned().await.expect("Semaphore is closed")),
+            None => None,
+        };
+        let global_permit = self
+            .jobs
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is closed");
+
+        if let Some(hl) = hl {
+            if !hl.delay.is_zero() {
+                // Lock is held while sleeping, so requests to the host are spread
+                // one by one
+                let mut next_start = hl.next_start.lock().await;
+                sleep_until(*next_start).await;
+                *next_start = Instant::now() + hl.delay;
+            }
+        }
+
+        Permit {
+            _global: global_permit,
+            _host: host_permit,
+        }
+    }
+}
+
+/// Time limits of a single git process.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TimeLimits {
+    /// Process is killed when it runs longer than this.
+    pub total: Option<Duration>,
+    /// Process is killed when it doesn't print anything for this long.
+    pub stall: Option<Duration>,
+}
+
+/// Timeouts of all git processes, per-repository ones take precedence over the
+/// global one.
+pub struct Timeouts {
+    total: Option<Duration>,
+    stall: Option<Duration>,
+    repos: HashMap<String, Duration>,
+}
+
+impl Timeouts {
+    pub fn new(total: Option<u64>, stall: Option<u64>, repos: &HashMap<String, u64>) -> Timeouts {
+        Timeouts {
+            total: total.map(Duration::from_secs),
+            stall: stall.map(Duration::from_secs),
+            repos: repos
+                .iter()
+                .map(|(r, t)| (r.clone(), Duration::from_secs(*t)))
+                .collect(),
+        }
+    }
+
+    /// Finds limits of the repository, per-repository timeout is matched either by
+    /// remote address or by the end of local path, e.g. `owner/repo`. Exact
+    /// address is used first, then the longest matching end of the path, so the
+    /// match doesn't depend on the order of config entries.
+    pub fn for_repo(&self, repo: &str, path: &Path) -> TimeLimits {
+        let total = self
+            .repos
+            .get(repo)
+            .or_else(|| {
+                self.repos
+                    .iter()
+                    .filter(|(r, _)| path.ends_with(r))
+                    .max_by_key(|(r, _)| Path::new(r).components().count())
+                    .map(|(_, t)| t)
+            })
+            .copied()
+            .or(self.total);
+
+        TimeLimits {
+            total,
+            stall: self.stall,
+        }
+    }
+}
+
+/// Longest delay between retries, regardless of the amount of attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// How many times and how soon transient failures are retried.
+#[derive(Copy, Clone, Debug)]
+pub struct Retries {
+    /// Amount of retries after the first attempt.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every next one.
+    pub delay: Duration,
+}
+
+impl Retries {
+    pub fn new(retries: u32, delay_ms: u64) -> Retries {
+        Retries {
+            retries,
+            delay: Duration::from_millis(delay_ms),
+        }
+    }
+
+    /// Exponential delay before retry after `attempt`, random half of it is
+    /// subtracted, so repositories failed together are not retried together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY);
+        let half = exp / 2;
+
+        half + half.mul_f64(random_fraction())
+    }
+}
+
+/// Random number in [0, 1), randomly seeded hasher is good enough for jitter.
+fn random_fraction() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / (u64::MAX as f64 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(delay_ms: u64, max_jobs: Option<usize>) -> HashMap<String, HostConfig> {
+        HashMap::from([(
+            "Host.com".to_string(),
+            HostConfig {
+                max_jobs,
+                delay_ms: Some(delay_ms),
+                ..HostConfig::default()
+            },
+        )])
+    }
+
+    /// Starts `n` processes of `host` holding their slot for `hold`, returns times
+    /// they were started at, from the start of the test.
+    async fn starts(lm: Arc<Limiter>, host: &'static str, n: usize, hold: u64) -> Vec<u64> {
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..n)
+            .map(|_| {
+                let lm = lm.clone();
+                tokio::spawn(async move {
+                    let _permit = lm.acquire(Some(host)).await;
+                    let at = started.elapsed().as_millis() as u64;
+                    tokio::time::sleep(Duration::from_millis(hold)).await;
+                    at
+                })
+            })
+            .collect();
+
+        let mut at = Vec::with_capacity(n);
+        for t in tasks {
+            at.push(t.await.unwrap());
+        }
+        at.sort_unstable();
+        at
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_host_delay() {
+        let lm = Arc::new(Limiter::new(8, &hosts(250, None)));
+        assert_eq!(starts(lm, "host.com", 4, 0).await, vec![0, 250, 500, 750]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_host_delay_when_jobs_are_saturated() {
+        let lm = Arc::new(Limiter::new(1, &hosts(250, None)));
+        let busy = {
+            let lm = lm.clone();
+            tokio::spawn(async move { starts(lm, "other.com", 1, 2000).await })
+        };
+        tokio::task::yield_now().await;
+
+        let at = starts(lm, "host.com", 4, 0).await;
+        assert_eq!(busy.await.unwrap(), vec![0]);
+        assert_eq!(at, vec![2000, 2250, 2500, 2750]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn limits_jobs_of_host() {
+        let lm = Arc::new(Limiter::new(8, &hosts(0, Some(2))));
+        assert_eq!(
+            starts(lm.clone(), "host.com", 4, 100).await,
+            vec![0, 0, 100, 100]
+        );
+        assert_eq!(starts(lm, "other.com", 4, 100).await, vec![0, 0, 0, 0]);
+    }
+
+    fn timeouts(repos: &[(&str, u64)]) -> Timeouts {
+        let repos = repos.iter().map(|(r, t)| (r.to_string(), *t)).collect();
+        Timeouts::new(Some(600), Some(60), &repos)
+    }
+
+    #[test]
+    fn prefers_repo_timeout_over_global() {
+        let to = timeouts(&[("torvalds/linux", 7200)]);
+        let tl = to.for_repo(
+            "https://host/torvalds/linux",
+            Path::new("/src/torvalds/linux"),
+        );
+        assert_eq!(tl.total, Some(Duration::from_secs(7200)));
+        assert_eq!(tl.stall, Some(Duration::from_secs(60)));
+
+        let tl = to.for_repo("https://host/o/r", Path::new("/src/o/r"));
+        assert_eq!(tl.total, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn prefers_exact_address_over_path() {
+        let to = timeouts(&[("r", 10), ("o/r", 20), ("https://host/o/r", 30)]);
+        let tl = to.for_repo("https://host/o/r", Path::new("/src/o/r"));
+        assert_eq!(tl.total, Some(Duration::from_secs(30)));
+
+        let tl = to.for_repo("git@host:o/r.git", Path::new("/src/o/r"));
+        assert_eq!(tl.total, Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn doubles_backoff_up_to_limit() {
+        let rt = Retries::new(10, 1000);
+        for (attempt, full) in [(1, 1000), (2, 2000), (3, 4000), (7, 60000), (40, 60000)] {
+            let full = Duration::from_millis(full);
+            for _ in 0..20 {
+                let delay = rt.backoff(attempt);
+                assert!(delay >= full / 2 && delay <= full, "{:?}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn spreads_backoff_with_jitter() {
+        let rt = Retries::new(2, 1000);
+        let delays: std::collections::HashSet<_> = (0..20).map(|_| rt.backoff(1)).collect();
+        assert!(delays.len() > 1);
+    }
+}
@@ -0,0 +1,210 @@
+//! Reads repository lists. Text files with repository on every line and csv files
+//! with a configurable column (`csv_column`) are supported at the moment. Every
+//! entry keeps the line it was read from, so problems can be pointed at.
+use crate::remote::RemoteAddr;
+use crate::secret;
+use csv::{Position, ReaderBuilder};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Entry of repository list with its line number, error describes entry which
+/// can't be parsed as remote address.
+pub struct Entry {
+    pub line: usize,
+    pub remote: Result<RemoteAddr, String>,
+}
+
+/// Checks whether list format is known by its extension, text files can have no
+/// extension at all.
+pub fn is_supported(path: &Path) -> bool {
+    let ext = path.extension().unwrap_or(OsStr::new(""));
+    ext == "txt" || ext == "csv" || ext.is_empty()
+}
+
+/// Reads every non-empty entry of the list. Unreadable files, unsupported formats
+/// and csv files without the requested column are errors of the whole list.
+pub fn read_list(path: &Path, cc: &str) -> Result<Vec<Entry>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not read: {} {}", path.display(), e))?;
+
+    match path.extension().unwrap_or(OsStr::new("")) {
+        ext if ext == "txt" || ext.is_empty() => read_txt(file),
+        ext if ext == "csv" => read_csv(path, file, cc),
+        _ => Err(format!("Unsupported list format: {}", path.display())),
+    }
+}
+
+fn read_txt(file: File) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::<Entry>::with_capacity(4096);
+
+    for (i, l) in BufReader::new(file).lines().enumerate() {
+        let remote = match l {
+            Ok(l) if l.trim().is_empty() => continue,
+            Ok(l) => parse(&l),
+            Err(e) => Err(format!("Could not read line: {}", e)),
+        };
+        entries.push(Entry {
+            line: i + 1,
+            remote,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn read_csv(path: &Path, mut file: File, cc: &str) -> Result<Vec<Entry>, String> {
+    let mut data = Vec::<u8>::new();
+    file.read_to_end(&mut data)
+        .map_err(|e| format!("Could not read: {} {}", path.display(), e))?;
+    // Line numbers of csv reader don't count blank lines, so lines are counted
+    // from the byte offset of the record, skipping blank lines before it
+    let line_at = |pos: Option<&Position>| {
+        let mut byte = pos.map(|p| p.byte() as usize).unwrap_or_default();
+        while matches!(data.get(byte), Some(b'\n' | b'\r')) {
+            byte += 1;
+        }
+        data[..byte.min(data.len())]
+            .iter()
+            .filter(|b| **b == b'\n')
+            .count()
+            + 1
+    };
+
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .from_reader(data.as_slice());
+    let headers = reader
+        .headers()
+        .map_err(|e| format!("Could not get header from csv: {} {}", path.display(), e))?;
+    let repo_pos = headers
+        .iter()
+        .position(|he| he.trim() == cc)
+        .ok_or(format!(
+            "Column \"{}\" is not found in csv header: {} ({})",
+            cc,
+            path.display(),
+            headers.iter().collect::<Vec<&str>>().join(", ")
+        ))?;
+
+    let mut entries = Vec::<Entry>::with_capacity(4096);
+    for rec in reader.into_records() {
+        let (line, remote) = match rec {
+            Ok(r) if r.is_empty() => continue,
+            Ok(r) => {
+                let line = line_at(r.position());
+                match r.get(repo_pos) {
+                    Some(re) if !re.trim().is_empty() => (line, parse(re)),
+                    _ => (line, Err("Could not get record element".to_string())),
+                }
+            }
+            Err(e) => {
+                let line = line_at(e.position());
+                (line, Err(format!("Could not get record: {}", e)))
+            }
+        };
+        entries.push(Entry { line, remote });
+    }
+
+    Ok(entries)
+}
+
+/// Parses remote address, error never contains credentials of the address.
+fn parse(addr: &str) -> Result<RemoteAddr, String> {
+    RemoteAddr::parse(addr).map_err(|e| {
+        format!(
+            "Could not parse remote address: {} {}",
+            secret::redact_urls(addr.trim()),
+            secret::redact_urls(&e)
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Writes list to temporary folder, file name is unique for every test.
+    fn list(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("freshgit-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Line and path of every entry, or error text.
+    fn entries(path: &Path, cc: &str) -> Vec<(usize, Result<Vec<String>, String>)> {
+        let en = read_list(path, cc).unwrap();
+        fs::remove_file(path).unwrap();
+        en.into_iter()
+            .map(|e| (e.line, e.remote.map(|r| r.path().to_vec())))
+            .collect()
+    }
+
+    fn repo(segments: &[&str]) -> Result<Vec<String>, String> {
+        Ok(segments.iter().map(|s| s.to_string()).collect())
+    }
+
+    #[test]
+    fn reads_csv_column() {
+        let path = list(
+            "column.csv",
+            "name,repository\na,https://host/o/a.git\nb,git@host:o/b.git\n",
+        );
+        assert_eq!(
+            entries(&path, "repository"),
+            vec![(2, repo(&["o", "a"])), (3, repo(&["o", "b"]))]
+        );
+    }
+
+    #[test]
+    fn fails_without_csv_column() {
+        let path = list("no-column.csv", "name,repo\na,https://host/o/a.git\n");
+        let err = read_list(&path, "repository").err().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            err,
+            format!(
+                "Column \"repository\" is not found in csv header: {} (name, repo)",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn reports_empty_csv_cells() {
+        let path = list(
+            "empty.csv",
+            "name,repository\na,\nb,https://host/o/b\n\nc,https://host/o/c\n",
+        );
+        let en = entries(&path, "repository");
+        assert_eq!(en.len(), 3);
+        assert_eq!(en[0].0, 2);
+        assert!(en[0].1.is_err());
+        assert_eq!(en[1], (3, repo(&["o", "b"])));
+        assert_eq!(en[2], (5, repo(&["o", "c"])));
+    }
+
+    #[test]
+    fn skips_blank_lines_of_txt() {
+        let path = list(
+            "blank.txt",
+            "https://host/o/a\n\n   \nnot a url\ngit@host:o/b.git\n",
+        );
+        let en = entries(&path, "repository");
+        assert_eq!(en.len(), 3);
+        assert_eq!(en[0], (1, repo(&["o", "a"])));
+        assert_eq!(en[1].0, 4);
+        assert!(en[1].1.is_err());
+        assert_eq!(en[2], (5, repo(&["o", "b"])));
+    }
+
+    #[test]
+    fn knows_supported_formats() {
+        assert!(is_supported(Path::new("repos.txt")));
+        assert!(is_supported(Path::new("repos.csv")));
+        assert!(is_supported(Path::new("repos")));
+        assert!(!is_supported(Path::new("repos.json")));
+    }
+}
@@ -1,21 +1,38 @@
 //! # freshgit - git repositories downloader and updater.
 //!
 //! This application is designed to download and update selected repositories locally.
+mod askpass;
+mod check;
+mod config_file;
+mod credentials;
 mod dl_upd;
+mod error;
+mod git_error;
 mod git_ops;
+mod init;
+mod limits;
+mod lists;
+mod overrides;
+mod remote;
+mod report;
+mod secret;
+mod ssh;
 use clap::{Arg, Command};
 use dl_upd::{download_repos, update_directories};
+use error::{FreshgitError, EXIT_ABORTED, EXIT_REPORT, EXIT_REPOS_FAILED};
 use log::{error, info};
 use simple_logger::SimpleLogger;
+use std::path::Path;
+use std::process::exit;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    SimpleLogger::new().init().unwrap();
     let m = Command::new("freshgit - git repositories downloader and updater")
         .author("flakusha, zenflak@gmail.com")
         .version(VERSION)
         .subcommand_required(true)
+        .subcommand_negates_reqs(true)
         .arg_required_else_help(true)
         .arg(
             Arg::new("config")
@@ -24,19 +41,58 @@ fn main() {
                 .value_name("CONF")
                 .takes_value(true)
                 .multiple_values(false)
-                .help("Path to configuration .json file")
-                .required(true),
+                .help("Path to configuration .json, .toml or .yaml file, overrides values of configs found in standard locations")
+                .required(false),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("JOBS")
+                .takes_value(true)
+                .multiple_values(false)
+                .validator(|j| j.parse::<usize>())
+                .help("Amount of git processes to run at once, overrides max_jobs of config")
+                .required(false),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("PATH")
+                .takes_value(true)
+                .multiple_values(false)
+                .help("Writes outcome of every repository to .json file")
+                .required(false),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .multiple_values(false)
+                .possible_values(["text", "json"])
+                .default_value("text")
+                .help("Format of the summary printed after the run")
+                .required(false),
+        )
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .value_name("NAME")
+                .takes_value(true)
+                .multiple_values(false)
+                .help("Profile of config to use")
+                .required(false),
         )
-        // .arg(
-        //     Arg::new("tasks")
-        //         .short('t')
-        //         .long("tasks")
-        //         .value_name("TASKS")
-        //         .takes_value(true)
-        //         .multiple_values(false)
-        //         .help("Amount of repos to process at once")
-        //         .required(false),
-        // )
+        .arg(
+            Arg::new("all-profiles")
+                .long("all-profiles")
+                .takes_value(false)
+                .conflicts_with("profile")
+                .help("Updates or downloads repositories of every profile in turn")
+                .required(false),
+        )
+        .args(overrides::args())
         .subcommand(
             Command::new("update")
                 .short_flag('u')
@@ -49,21 +105,167 @@ fn main() {
                 .long_flag("download")
                 .about("Downloads git repositories provided in config file"),
         )
-        .get_matches();
+        .subcommand(
+            Command::new("check-config")
+                .about("Checks config and repository lists without running git"),
+        )
+        .subcommand(
+            Command::new("init")
+                .about("Asks questions and writes new commented config")
+                .arg(
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .help("Toml file to write, ./freshgit.toml by default")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Works with config files")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("show")
+                        .about("Prints effective config merged from all config files")
+                        .arg(
+                            Arg::new("origin")
+                                .long("origin")
+                                .takes_value(false)
+                                .help("Shows file every value was taken from"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("convert")
+                        .about("Converts config to format of the output file extension")
+                        .arg(
+                            Arg::new("from")
+                                .value_name("FROM")
+                                .help("Config file to convert, .json, .toml, .yaml or .yml")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("to")
+                                .value_name("TO")
+                                .help("File to write, it must not exist")
+                                .required(true),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new(askpass::SUBCOMMAND)
+                .hide(true)
+                .about("Answers prompts of git and ssh, used as GIT_ASKPASS/SSH_ASKPASS")
+                .arg(Arg::new("prompt").takes_value(true).required(false)),
+        )
+        .get_matches_from(askpass::args(std::env::args_os()));
 
+    // Askpass output is read by git, so nothing else is printed
+    if let Some((askpass::SUBCOMMAND, ap)) = m.subcommand() {
+        exit(askpass::run(ap.value_of("prompt").unwrap_or_default()));
+    }
+
+    SimpleLogger::new().init().unwrap();
     info!("Checking subcommands");
 
-    match m.subcommand() {
+    let report = m.value_of("report").map(|r| r.to_string());
+    let format = m.value_of("format").unwrap_or("text").to_string();
+
+    if let Some(("init", ini)) = m.subcommand() {
+        match init::init(ini.value_of("path").map(Path::new)) {
+            Ok(()) => exit(0),
+            Err(e) => {
+                error!("{}", e);
+                exit(e.exit_code());
+            }
+        }
+    }
+
+    if let Some(("check-config", _chk)) = m.subcommand() {
+        info!("Checking config");
+        match check::check_config(&m) {
+            Ok(0) => exit(0),
+            Ok(_) => exit(EXIT_ABORTED),
+            Err(e) => {
+                error!("{}", e);
+                exit(e.exit_code());
+            }
+        }
+    }
+
+    if let Some(("config", cfg)) = m.subcommand() {
+        if let Some(("show", shw)) = cfg.subcommand() {
+            let layers =
+                config_file::Layers::load(m.value_of("config").map(Path::new)).and_then(|mut l| {
+                    l.select_profile(m.value_of("profile"))?;
+                    overrides::apply(&mut l, &m).map(|_| l)
+                });
+            match layers {
+                Ok(l) => l.show(shw.is_present("origin")),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(e.exit_code());
+                }
+            }
+        }
+        if let Some(("convert", cnv)) = cfg.subcommand() {
+            let from = Path::new(cnv.value_of("from").unwrap_or_default());
+            let to = Path::new(cnv.value_of("to").unwrap_or_default());
+            match config_file::convert(from, to) {
+                Ok(()) => info!("Config {} is converted to {}", from.display(), to.display()),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(e.exit_code());
+                }
+            }
+        }
+        exit(0);
+    }
+
+    let summary = match m.subcommand() {
         Some(("update", _upd)) => {
             info!("Starting repositories update");
-            update_directories(m);
+            update_directories(m)
         }
         Some(("download", _dwl)) => {
             info!("Starting repositories download");
-            download_repos(m);
+            download_repos(m)
+        }
+        _ => Err(FreshgitError::Aborted),
+    };
+
+    match summary {
+        Ok(s) => {
+            match format.as_str() {
+                "json" => println!("{}", s.to_json()),
+                _ => s.print(),
+            }
+            let reported = match report {
+                Some(r) => match s.write_report(&r) {
+                    Ok(()) => {
+                        info!("Report is written to: {}", r);
+                        true
+                    }
+                    Err(e) => {
+                        error!("Could not write report {}: {}", r, e);
+                        false
+                    }
+                },
+                None => true,
+            };
+            if s.failed() > 0 {
+                error!("{} repositories failed", s.failed());
+                exit(EXIT_REPOS_FAILED);
+            }
+            if !reported {
+                exit(EXIT_REPORT);
+            }
+            if !s.aborted.is_empty() {
+                exit(EXIT_ABORTED);
+            }
         }
-        _ => {
-            error!("Incorrect config is provided");
+        Err(e) => {
+            error!("{}", e);
+            exit(e.exit_code());
         }
     }
 }
@@ -0,0 +1,258 @@
+//! Overrides of config values for a single run, without editing config files.
+//! Every field can be set with `FRESHGIT_<FIELD>` environment variable and with
+//! its command line flag, and any value, including values of hosts, with
+//! `--set key=value`. Environment variables override config files, flags override
+//! environment variables and `--set` overrides flags.
+use crate::config_file::Layers;
+use crate::error::FreshgitError;
+use clap::{Arg, ArgMatches};
+use serde_json::Value;
+use std::env;
+
+/// Prefix of environment variables of config fields.
+const ENV_PREFIX: &str = "FRESHGIT_";
+/// Id of `--set` argument.
+const SET: &str = "set";
+/// Separator of list values in flags and environment variables.
+const LIST_SEPARATOR: char = ',';
+
+/// Config field which can be overridden with flag.
+struct Field {
+    name: &'static str,
+    /// Id and long name of the flag.
+    flag: &'static str,
+    /// Value is a list, separated by `LIST_SEPARATOR`.
+    list: bool,
+    help: &'static str,
+}
+
+const fn field(name: &'static str, flag: &'static str, help: &'static str) -> Field {
+    Field {
+        name,
+        flag,
+        list: false,
+        help,
+    }
+}
+
+/// Fields with flags, `max_jobs` uses `-j/--jobs` defined with other arguments.
+const FIELDS: [Field; 15] = [
+    field(
+        "src_folder",
+        "src-folder",
+        "Folder repositories are cloned to",
+    ),
+    Field {
+        name: "files_to_read",
+        flag: "files-to-read",
+        list: true,
+        help: "Comma separated repository lists",
+    },
+    field("git_username", "git-username", "Username of git remotes"),
+    field(
+        "git_password",
+        "git-password",
+        "Password of git remotes, use ${env:NAME} reference to keep it out of process list",
+    ),
+    field(
+        "ssh_askpass",
+        "ssh-askpass",
+        "SSH key passphrase, use ${env:NAME} reference to keep it out of process list",
+    ),
+    field(
+        "async_exec",
+        "async-exec",
+        "Runs git processes at once, true or false",
+    ),
+    field(
+        "csv_column",
+        "csv-column",
+        "Column of csv lists with repositories",
+    ),
+    field("max_jobs", "jobs", "Amount of git processes to run at once"),
+    field("hosts", "hosts", "Json object with settings of hosts"),
+    field(
+        "timeout_secs",
+        "timeout-secs",
+        "Maximal time git is allowed to run",
+    ),
+    field(
+        "stall_timeout_secs",
+        "stall-timeout-secs",
+        "Kills git which had no output for this long",
+    ),
+    field(
+        "repo_timeouts",
+        "repo-timeouts",
+        "Json object with timeouts of repositories",
+    ),
+    field(
+        "retries",
+        "retries",
+        "Amount of retries of transient failures",
+    ),
+    field(
+        "retry_delay_ms",
+        "retry-delay-ms",
+        "Delay before the first retry",
+    ),
+    field(
+        "credential_helper",
+        "credential-helper",
+        "Credential helper of https remotes",
+    ),
+];
+
+/// Flags of config fields and `--set`.
+pub fn args() -> Vec<Arg<'static>> {
+    let mut args: Vec<Arg> = FIELDS
+        .iter()
+        .filter(|f| f.name != "max_jobs")
+        .map(|f| {
+            Arg::new(f.flag)
+                .long(f.flag)
+                .value_name("VALUE")
+                .takes_value(true)
+                .multiple_values(false)
+                .help(f.help)
+                .required(false)
+        })
+        .collect();
+    args.push(
+        Arg::new(SET)
+            .long(SET)
+            .value_name("KEY=VALUE")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .help("Sets any config value, e.g. hosts.\"github.com\".max_jobs=2")
+            .required(false),
+    );
+
+    args
+}
+
+/// Applies environment variables, flags and `--set` values on top of config files.
+pub fn apply(layers: &mut Layers, matches: &ArgMatches) -> Result<(), FreshgitError> {
+    for f in FIELDS.iter() {
+        let var = format!("{}{}", ENV_PREFIX, f.name.to_uppercase());
+        if let Some(raw) = env::var(&var).ok().filter(|v| !v.is_empty()) {
+            set(layers, &[f.name.to_string()], &raw, &var)?;
+        }
+    }
+    for f in FIELDS.iter() {
+        if let Some(raw) = matches.value_of(f.flag) {
+            set(layers, &[f.name.to_string()], raw, &format!("--{}", f.flag))?;
+        }
+    }
+    for s in matches.values_of(SET).into_iter().flatten() {
+        let (key, raw) = s.split_once('=').ok_or(FreshgitError::value(
+            "--set",
+            s,
+            "has to be written as key=value".to_string(),
+        ))?;
+        let path = key_path(key).ok_or(FreshgitError::value(
+            "--set",
+            key,
+            "is not valid key".to_string(),
+        ))?;
+        set(layers, &path, raw, "--set")?;
+    }
+
+    Ok(())
+}
+
+/// Sets raw value of `path`, value is taken as json if it's valid for the field,
+/// e.g. number or `true`, and as text otherwise. Lists of fields are split.
+fn set(layers: &mut Layers, path: &[String], raw: &str, origin: &str) -> Result<(), FreshgitError> {
+    let mut values = Vec::<Value>::with_capacity(3);
+    if let Ok(v) = serde_json::from_str(raw) {
+        values.push(v);
+    }
+    let list = matches!(path, [p] if FIELDS.iter().any(|f| f.list && f.name == p));
+    if list {
+        values.push(Value::Array(
+            raw.split(LIST_SEPARATOR)
+                .map(|v| Value::String(v.trim().to_string()))
+                .collect(),
+        ));
+    }
+    values.push(Value::String(raw.to_string()));
+
+    layers
+        .set(path, values, origin)
+        .map_err(|e| FreshgitError::value(origin, &path.join("."), e))
+}
+
+/// Splits dotted key into names, names with dots are quoted, e.g.
+/// `hosts."github.com".max_jobs`. `None` if key has empty names or open quote.
+fn key_path(key: &str) -> Option<Vec<String>> {
+    let mut path = Vec::<String>::new();
+    let mut rest = key.trim();
+    while !rest.is_empty() {
+        let (name, tail) = match rest.strip_prefix('"') {
+            Some(q) => {
+                let end = q.find('"')?;
+                (&q[..end], &q[end + 1..])
+            }
+            None => rest.split_at(rest.find('.').unwrap_or(rest.len())),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        path.push(name.to_string());
+        rest = match tail.strip_prefix('.') {
+            Some(t) if !t.is_empty() => t,
+            Some(_) => return None,
+            None if tail.is_empty() => tail,
+            None => return None,
+        };
+    }
+
+    match path.is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(p: &[&str]) -> Option<Vec<String>> {
+        Some(p.iter().map(|k| k.to_string()).collect())
+    }
+
+    #[test]
+    fn splits_nested_keys() {
+        assert_eq!(key_path("max_jobs"), path(&["max_jobs"]));
+        assert_eq!(
+            key_path("hosts.\"github.com\".max_jobs"),
+            path(&["hosts", "github.com", "max_jobs"])
+        );
+        assert_eq!(
+            key_path("hosts.\"git.example.com:8443\".ssh_key.identity"),
+            path(&["hosts", "git.example.com:8443", "ssh_key", "identity"])
+        );
+        assert_eq!(
+            key_path("repo_timeouts.\"torvalds/linux\""),
+            path(&["repo_timeouts", "torvalds/linux"])
+        );
+        assert_eq!(key_path(" hosts.localhost "), path(&["hosts", "localhost"]));
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        for key in [
+            "",
+            ".",
+            "hosts.",
+            ".hosts",
+            "hosts..max_jobs",
+            "hosts.\"github.com",
+            "hosts.\"\".max_jobs",
+            "hosts.\"github.com\"max_jobs",
+        ] {
+            assert_eq!(key_path(key), None, "{}", key);
+        }
+    }
+}
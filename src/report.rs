@@ -0,0 +1,388 @@
+//! Collects outcome of every processed repository to summarize the run and to
+//! write machine-readable report.
+use crate::git_error::GitError;
+use crate::git_ops::GitMode;
+use core::fmt;
+use serde::Serialize;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What happened to the repository during the run.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Cloned,
+    Fetched,
+    UpToDate,
+    /// Clone target already exists, update should be used instead.
+    AlreadyExists,
+    Failed(String),
+    /// Git was killed because it ran or stalled for too long.
+    TimedOut(String),
+}
+
+impl Outcome {
+    /// Short name of the outcome used in reports.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Cloned => "cloned",
+            Outcome::Fetched => "fetched",
+            Outcome::UpToDate => "up-to-date",
+            Outcome::AlreadyExists => "already-exists",
+            Outcome::Failed(_) => "failed",
+            Outcome::TimedOut(_) => "timed-out",
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Cloned => write!(f, "cloned"),
+            Outcome::Fetched => write!(f, "fetched"),
+            Outcome::UpToDate => write!(f, "up-to-date"),
+            Outcome::AlreadyExists => write!(f, "skipped, already exists"),
+            Outcome::Failed(reason) => write!(f, "failed: {}", reason),
+            Outcome::TimedOut(reason) => write!(f, "timed out: {}", reason),
+        }
+    }
+}
+
+/// Outcome of a single repository.
+#[derive(Clone, Debug)]
+pub struct RepoResult {
+    /// Remote address, `origin` remote is used for fetch if it's available.
+    pub repo: String,
+    pub path: PathBuf,
+    pub mode: GitMode,
+    pub outcome: Outcome,
+    /// Time spent running git for this repository.
+    pub duration: Duration,
+    /// Exit code of git, `None` if git was not run or was killed by signal.
+    pub exit_code: Option<i32>,
+    /// Commit before the run, upstream of current branch is used for fetch if
+    /// it's configured, otherwise HEAD.
+    pub old_head: Option<String>,
+    /// Commit after the run, resolved the same way as `old_head`.
+    pub new_head: Option<String>,
+    /// Amount of times git was run, transient failures are retried.
+    pub attempts: u32,
+    /// Category of failure, `None` for successful or timed out repositories.
+    pub error_kind: Option<GitError>,
+    /// Last lines of git stderr, kept for failed repositories only.
+    pub stderr: Vec<String>,
+    /// Profile of config the repository was processed with.
+    pub profile: Option<String>,
+}
+
+impl RepoResult {
+    pub fn new(repo: String, path: PathBuf, mode: GitMode, outcome: Outcome) -> RepoResult {
+        RepoResult {
+            repo,
+            path,
+            mode,
+            outcome,
+            duration: Duration::ZERO,
+            exit_code: None,
+            old_head: None,
+            new_head: None,
+            attempts: 0,
+            error_kind: None,
+            stderr: Vec::new(),
+            profile: None,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self.outcome, Outcome::Failed(_) | Outcome::TimedOut(_))
+    }
+}
+
+/// Entry of json report, see `RepoResult` for field descriptions.
+#[derive(Serialize)]
+struct ReportEntry<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a str>,
+    url: &'a str,
+    path: &'a Path,
+    mode: GitMode,
+    result: &'static str,
+    duration_ms: u128,
+    attempts: u32,
+    exit_code: Option<i32>,
+    error: Option<&'a str>,
+    error_kind: Option<GitError>,
+    stderr: &'a [String],
+    old_head: Option<&'a str>,
+    new_head: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    total: usize,
+    failed: usize,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    aborted_profiles: &'a [String],
+    repositories: Vec<ReportEntry<'a>>,
+}
+
+/// Outcomes of all repositories processed during the run.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub results: Vec<RepoResult>,
+    /// Profiles which were not run because of config problems.
+    pub aborted: Vec<String>,
+}
+
+impl Summary {
+    /// Adds outcomes of the run with `profile` to the summary.
+    pub fn add(&mut self, profile: Option<&str>, summary: Summary) {
+        self.results
+            .extend(summary.results.into_iter().map(|mut r| {
+                r.profile = profile.map(|p| p.to_string());
+                r
+            }));
+        self.aborted.extend(summary.aborted);
+    }
+
+    pub fn failed(&self) -> usize {
+        self.results.iter().filter(|r| r.is_failed()).count()
+    }
+
+    /// Prints table with outcome of every repository followed by totals.
+    pub fn print(&self) {
+        let rw = self
+            .results
+            .iter()
+            .map(|r| r.repo.chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("Repository".len());
+        let pw = self
+            .results
+            .iter()
+            .map(|r| r.path.to_string_lossy().chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("Path".len());
+        // Profile column is only shown if profiles were used
+        let fw = self
+            .results
+            .iter()
+            .filter_map(|r| r.profile.as_deref())
+            .map(|p| p.chars().count())
+            .max()
+            .map_or(0, |w| w.max("Profile".len()) + 2);
+        let profile = |p: &str| match fw {
+            0 => String::new(),
+            _ => format!("{:<fw$}", p, fw = fw),
+        };
+
+        println!(
+            "{}{:<rw$}  {:<pw$}  Result",
+            profile("Profile"),
+            "Repository",
+            "Path",
+            rw = rw,
+            pw = pw
+        );
+        for r in self.results.iter() {
+            println!(
+                "{}{:<rw$}  {:<pw$}  {}",
+                profile(r.profile.as_deref().unwrap_or_default()),
+                r.repo,
+                r.path.to_string_lossy(),
+                r.outcome,
+                rw = rw,
+                pw = pw
+            );
+        }
+
+        let count = |o: fn(&Outcome) -> bool| self.results.iter().filter(|r| o(&r.outcome)).count();
+        println!(
+            "Total: {}, cloned: {}, fetched: {}, up-to-date: {}, already exist: {}, timed out: {}, failed: {}",
+            self.results.len(),
+            count(|o| *o == Outcome::Cloned),
+            count(|o| *o == Outcome::Fetched),
+            count(|o| *o == Outcome::UpToDate),
+            count(|o| *o == Outcome::AlreadyExists),
+            count(|o| matches!(o, Outcome::TimedOut(_))),
+            count(|o| matches!(o, Outcome::Failed(_)))
+        );
+        if !self.aborted.is_empty() {
+            println!("Aborted profiles: {}", self.aborted.join(", "));
+        }
+    }
+
+    /// Serializes outcomes of all repositories as pretty printed json.
+    pub fn to_json(&self) -> String {
+        let repositories = self
+            .results
+            .iter()
+            .map(|r| ReportEntry {
+                profile: r.profile.as_deref(),
+                url: &r.repo,
+                path: &r.path,
+                mode: r.mode,
+                result: r.outcome.name(),
+                duration_ms: r.duration.as_millis(),
+                attempts: r.attempts,
+                exit_code: r.exit_code,
+                error: match &r.outcome {
+                    Outcome::Failed(reason) | Outcome::TimedOut(reason) => Some(reason),
+                    _ => None,
+                },
+                error_kind: r.error_kind,
+                stderr: &r.stderr,
+                old_head: r.old_head.as_deref(),
+                new_head: r.new_head.as_deref(),
+            })
+            .collect();
+        let report = Report {
+            total: self.results.len(),
+            failed: self.failed(),
+            aborted_profiles: &self.aborted,
+            repositories,
+        };
+
+        serde_json::to_string_pretty(&report).expect("Report is always serializable")
+    }
+
+    /// Writes json report to the file, overwriting it.
+    pub fn write_report<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(self.to_json().as_bytes())?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn result(repo: &str, outcome: Outcome) -> RepoResult {
+        RepoResult::new(
+            repo.to_string(),
+            PathBuf::from("/src/o").join(repo),
+            GitMode::CLONE,
+            outcome,
+        )
+    }
+
+    #[test]
+    fn counts_failed_and_timed_out() {
+        let summary = Summary {
+            results: vec![
+                result("a", Outcome::Cloned),
+                result("b", Outcome::AlreadyExists),
+                result("c", Outcome::Failed("no".to_string())),
+                result("d", Outcome::TimedOut("slow".to_string())),
+            ],
+            aborted: Vec::new(),
+        };
+        assert_eq!(summary.failed(), 2);
+    }
+
+    #[test]
+    fn adds_profile_to_results() {
+        let mut summary = Summary::default();
+        summary.add(
+            Some("work"),
+            Summary {
+                results: vec![result("a", Outcome::Cloned)],
+                aborted: Vec::new(),
+            },
+        );
+        summary.add(
+            None,
+            Summary {
+                results: vec![result("b", Outcome::Cloned)],
+                aborted: vec!["oss".to_string()],
+            },
+        );
+
+        assert_eq!(summary.results[0].profile.as_deref(), Some("work"));
+        assert_eq!(summary.results[1].profile, None);
+        assert_eq!(summary.aborted, vec!["oss".to_string()]);
+    }
+
+    #[test]
+    fn writes_json_report() {
+        let mut ok = result("a", Outcome::Cloned);
+        ok.duration = Duration::from_millis(1500);
+        ok.attempts = 1;
+        ok.exit_code = Some(0);
+        ok.new_head = Some("abc".to_string());
+        let mut failed = result("b", Outcome::Failed("repository not found".to_string()));
+        failed.attempts = 1;
+        failed.exit_code = Some(128);
+        failed.error_kind = Some(GitError::NotFound);
+        failed.stderr = vec!["fatal: repository not found".to_string()];
+
+        let summary = Summary {
+            results: vec![ok, failed],
+            aborted: Vec::new(),
+        };
+        let report: Value = serde_json::from_str(&summary.to_json()).unwrap();
+
+        assert_eq!(
+            report,
+            json!({
+                "total": 2,
+                "failed": 1,
+                "repositories": [
+                    {
+                        "url": "a",
+                        "path": "/src/o/a",
+                        "mode": "clone",
+                        "result": "cloned",
+                        "duration_ms": 1500,
+                        "attempts": 1,
+                        "exit_code": 0,
+                        "error": null,
+                        "error_kind": null,
+                        "stderr": [],
+                        "old_head": null,
+                        "new_head": "abc"
+                    },
+                    {
+                        "url": "b",
+                        "path": "/src/o/b",
+                        "mode": "clone",
+                        "result": "failed",
+                        "duration_ms": 0,
+                        "attempts": 1,
+                        "exit_code": 128,
+                        "error": "repository not found",
+                        "error_kind": "not-found",
+                        "stderr": ["fatal: repository not found"],
+                        "old_head": null,
+                        "new_head": null
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn writes_profiles_to_json_report() {
+        let mut summary = Summary::default();
+        summary.add(
+            Some("work"),
+            Summary {
+                results: vec![result("a", Outcome::TimedOut("slow".to_string()))],
+                aborted: vec!["oss".to_string()],
+            },
+        );
+        let report: Value = serde_json::from_str(&summary.to_json()).unwrap();
+
+        assert_eq!(report["aborted_profiles"], json!(["oss"]));
+        assert_eq!(report["repositories"][0]["profile"], json!("work"));
+        assert_eq!(report["repositories"][0]["result"], json!("timed-out"));
+        assert_eq!(report["repositories"][0]["error"], json!("slow"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Picks SSH key of the repository by its host and owner, and builds ssh command
+//! git runs with it through `GIT_SSH_COMMAND`. Command of the user, set in
+//! environment or in git config, is kept and only options are added to it.
+use crate::dl_upd::{HostConfig, SshKey};
+use crate::remote::RemoteAddr;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+
+const ENV_GIT_SSH_COMMAND: &str = "GIT_SSH_COMMAND";
+const ENV_GIT_SSH: &str = "GIT_SSH";
+
+/// SSH keys of a single host.
+struct HostKeys {
+    key: Option<SshKey>,
+    /// Owner path split into folder names, with its key.
+    owners: Vec<(Vec<String>, SshKey)>,
+}
+
+pub struct SshKeys {
+    hosts: HashMap<String, HostKeys>,
+}
+
+impl SshKeys {
+    /// Creates key selection from `ssh_key` and `owner_ssh_keys` of host entries.
+    pub fn new(hosts: &HashMap<String, HostConfig>) -> SshKeys {
+        let hosts = hosts
+            .iter()
+            .filter(|(_, hc)| hc.ssh_key.is_some() || hc.owner_ssh_keys.is_some())
+            .map(|(h, hc)| {
+                let mut owners: Vec<(Vec<String>, SshKey)> = hc
+                    .owner_ssh_keys
+                    .iter()
+                    .flatten()
+                    .map(|(o, k)| {
+                        let segments = o
+                            .split('/')
+                            .filter(|s| !s.is_empty())
+                            .map(|s| s.to_string())
+                            .collect();
+                        (segments, k.clone())
+                    })
+                    .collect();
+                // Longer owners are checked first, so the most specific one is used
+                owners.sort_by_key(|(o, _)| std::cmp::Reverse(o.len()));
+
+                let hk = HostKeys {
+                    key: hc.ssh_key.clone(),
+                    owners,
+                };
+                (h.to_lowercase(), hk)
+            })
+            .collect();
+
+        SshKeys { hosts }
+    }
+
+    /// Finds key of ssh remote, key of the longest matching owner takes precedence
+    /// over key of the host.
+    pub fn for_remote(&self, ra: &RemoteAddr) -> Option<&SshKey> {
+        if !ra.is_ssh() {
+            return None;
+        }
+        let hk = self.hosts.get(ra.host()?)?;
+
+        hk.owners
+            .iter()
+            .find(|(o, _)| !o.is_empty() && ra.path().starts_with(o))
+            .map(|(_, k)| k)
+            .or(hk.key.as_ref())
+    }
+}
+
+/// Resolves ssh command git would run for repository in `cd`, the same way git
+/// does: `GIT_SSH_COMMAND`, `core.sshCommand` of the repository or of the user,
+/// `GIT_SSH` and `ssh`. Without `cd` config of the user is read, as for clone.
+pub async fn base_command(cd: Option<&Path>) -> String {
+    if let Some(sc) = env::var(ENV_GIT_SSH_COMMAND).ok().filter(|c| !c.is_empty()) {
+        return sc;
+    }
+
+    // Temporary folder is outside of any repository, so only config of the user
+    // and of the system is read there
+    let out = Command::new("git")
+        .current_dir(cd.map(|c| c.to_path_buf()).unwrap_or(env::temp_dir()))
+        .args(["config", "--get", "core.sshCommand"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .await
+        .ok()
+        .filter(|o| o.status.success());
+    if let Some(out) = out {
+        let sc = String::from_utf8_lossy(&out.stdout).trim().to_string();
+        if !sc.is_empty() {
+            return sc;
+        }
+    }
+
+    // GIT_SSH is a program run without shell
+    match env::var(ENV_GIT_SSH).ok().filter(|p| !p.is_empty()) {
+        Some(p) => shell_quote(&p),
+        None => "ssh".to_string(),
+    }
+}
+
+/// Builds `GIT_SSH_COMMAND` from `base` command, adding identity of `key` and its
+/// options, and batch mode if `batch` is set. `None` if nothing has to be added.
+pub fn ssh_command(base: &str, key: Option<&SshKey>, batch: bool) -> Option<String> {
+    if key.is_none() && !batch {
+        return None;
+    }
+
+    let mut cmd = base.to_string();
+    if let Some(k) = key {
+        cmd.push_str(" -i ");
+        cmd.push_str(&shell_quote(&expand_home(&k.identity).to_string_lossy()));
+        // Only this key is offered, keys of ssh agent would be tried first otherwise
+        cmd.push_str(" -o IdentitiesOnly=yes");
+        for o in k.options.iter().flatten() {
+            cmd.push_str(" -o ");
+            cmd.push_str(&shell_quote(o));
+        }
+    }
+    if batch {
+        cmd.push_str(" -o BatchMode=yes");
+    }
+
+    Some(cmd)
+}
+
+/// Replaces leading `~` with home folder, since `GIT_SSH_COMMAND` quoted paths are
+/// not expanded by shell.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Quotes argument for `sh`, git runs `GIT_SSH_COMMAND` with shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(identity: &str, options: &[&str]) -> SshKey {
+        SshKey {
+            identity: PathBuf::from(identity),
+            options: match options {
+                [] => None,
+                o => Some(o.iter().map(|o| o.to_string()).collect()),
+            },
+        }
+    }
+
+    fn keys() -> SshKeys {
+        let hc = HostConfig {
+            ssh_key: Some(key("/k/host", &[])),
+            owner_ssh_keys: Some(HashMap::from([
+                ("org".to_string(), key("/k/org", &[])),
+                ("org/team/".to_string(), key("/k/team", &[])),
+            ])),
+            ..HostConfig::default()
+        };
+        SshKeys::new(&HashMap::from([("Host.com".to_string(), hc)]))
+    }
+
+    fn identity(sk: &SshKeys, addr: &str) -> Option<PathBuf> {
+        let ra = RemoteAddr::parse(addr).unwrap();
+        sk.for_remote(&ra).map(|k| k.identity.clone())
+    }
+
+    #[test]
+    fn prefers_longest_owner_key() {
+        let sk = keys();
+        let id = |addr| identity(&sk, addr).map(|p| p.to_string_lossy().to_string());
+        assert_eq!(
+            id("git@host.com:org/team/r.git").as_deref(),
+            Some("/k/team")
+        );
+        assert_eq!(
+            id("ssh://git@host.com/org/r.git").as_deref(),
+            Some("/k/org")
+        );
+        assert_eq!(id("git@host.com:other/r.git").as_deref(), Some("/k/host"));
+        assert_eq!(id("git@other.com:org/r.git"), None);
+    }
+
+    #[test]
+    fn has_no_key_for_https() {
+        assert_eq!(identity(&keys(), "https://host.com/org/team/r.git"), None);
+    }
+
+    #[test]
+    fn adds_key_and_options_to_command() {
+        let k = key("/k/my key", &["Port=2222", "ProxyJump=a b"]);
+        assert_eq!(
+            ssh_command("ssh -v", Some(&k), false).as_deref(),
+            Some("ssh -v -i '/k/my key' -o IdentitiesOnly=yes -o 'Port=2222' -o 'ProxyJump=a b'")
+        );
+        assert_eq!(
+            ssh_command("ssh", Some(&key("/k/id", &[])), true).as_deref(),
+            Some("ssh -i '/k/id' -o IdentitiesOnly=yes -o BatchMode=yes")
+        );
+        assert_eq!(
+            ssh_command("ssh", None, true).as_deref(),
+            Some("ssh -o BatchMode=yes")
+        );
+        assert_eq!(ssh_command("ssh", None, false), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn quotes_for_shell() {
+        let arg = "it's a \"key\" $HOME";
+        assert_eq!(shell_quote(arg), "'it'\\''s a \"key\" $HOME'");
+
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote(arg)))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&out.stdout), arg);
+    }
+}